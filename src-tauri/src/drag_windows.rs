@@ -0,0 +1,393 @@
+//! OLE drag source behind `start_drag_export`. The data object advertises a
+//! single file (`CF_HDROP`) but only converts the image the first time a drop
+//! target asks for its data, which is Windows' delayed rendering. Written
+//! against the raw COM ABI, like the other Win32 calls in this crate.
+
+use std::ffi::c_void;
+use std::os::windows::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::{export_for_drag, DragExport};
+
+type Hresult = i32;
+
+const S_OK: Hresult = 0;
+const E_NOTIMPL: Hresult = 0x8000_4001_u32 as i32;
+const E_NOINTERFACE: Hresult = 0x8000_4002_u32 as i32;
+const E_FAIL: Hresult = 0x8000_4005_u32 as i32;
+const E_OUTOFMEMORY: Hresult = 0x8007_000E_u32 as i32;
+const DV_E_FORMATETC: Hresult = 0x8004_0064_u32 as i32;
+const OLE_E_ADVISENOTSUPPORTED: Hresult = 0x8004_0003_u32 as i32;
+const DATA_S_SAMEFORMATETC: Hresult = 0x0004_0130;
+const DRAGDROP_S_DROP: Hresult = 0x0004_0100;
+const DRAGDROP_S_CANCEL: Hresult = 0x0004_0101;
+const DRAGDROP_S_USEDEFAULTCURSORS: Hresult = 0x0004_0102;
+
+const CF_HDROP: u16 = 15;
+const TYMED_HGLOBAL: u32 = 1;
+const DVASPECT_CONTENT: u32 = 1;
+const DATADIR_GET: u32 = 1;
+const DROPEFFECT_NONE: u32 = 0;
+const DROPEFFECT_COPY: u32 = 1;
+const MK_LBUTTON: u32 = 0x1;
+const MK_RBUTTON: u32 = 0x2;
+const GMEM_MOVEABLE: u32 = 0x2;
+
+#[repr(C)]
+#[derive(PartialEq, Eq)]
+struct Guid(u32, u16, u16, [u8; 8]);
+
+const COM_BASE_IID_TAIL: [u8; 8] = [0xC0, 0, 0, 0, 0, 0, 0, 0x46];
+const IID_IUNKNOWN: Guid = Guid(0x0000_0000, 0, 0, COM_BASE_IID_TAIL);
+const IID_IDATAOBJECT: Guid = Guid(0x0000_010E, 0, 0, COM_BASE_IID_TAIL);
+const IID_IDROPSOURCE: Guid = Guid(0x0000_0121, 0, 0, COM_BASE_IID_TAIL);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FormatEtc {
+    cf_format: u16,
+    ptd: *mut c_void,
+    aspect: u32,
+    lindex: i32,
+    tymed: u32,
+}
+
+/// The only format offered: a file list in global memory.
+const HDROP_FORMAT: FormatEtc = FormatEtc {
+    cf_format: CF_HDROP,
+    ptd: null_mut(),
+    aspect: DVASPECT_CONTENT,
+    lindex: -1,
+    tymed: TYMED_HGLOBAL,
+};
+
+#[repr(C)]
+struct StgMedium {
+    tymed: u32,
+    handle: *mut c_void,
+    unk_for_release: *mut c_void,
+}
+
+/// `DROPFILES` header; the double-NUL-terminated UTF-16 path list follows it.
+#[repr(C)]
+struct DropFiles {
+    files_offset: u32,
+    x: i32,
+    y: i32,
+    non_client: i32,
+    wide: i32,
+}
+
+#[link(name = "ole32")]
+extern "system" {
+    fn OleInitialize(reserved: *mut c_void) -> Hresult;
+    fn OleUninitialize();
+    fn DoDragDrop(data: *mut c_void, source: *mut c_void, ok_effects: u32, effect: *mut u32) -> Hresult;
+}
+
+#[link(name = "shell32")]
+extern "system" {
+    fn SHCreateStdEnumFmtEtc(count: u32, formats: *const FormatEtc, out: *mut *mut c_void) -> Hresult;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GlobalAlloc(flags: u32, bytes: usize) -> *mut c_void;
+    fn GlobalLock(mem: *mut c_void) -> *mut c_void;
+    fn GlobalUnlock(mem: *mut c_void) -> i32;
+    fn GlobalFree(mem: *mut c_void) -> *mut c_void;
+}
+
+/// Runs a modal OLE drag of `source` and returns the file a drop target took,
+/// if any. Must be called on the UI thread. The conversion happens inside
+/// `GetData`, so the window is busy while a dropped HEIC/RAW is encoded.
+pub(crate) fn drag_with_delayed_export(
+    source: PathBuf,
+    format: Option<&'static str>,
+) -> Result<Option<DragExport>, String> {
+    unsafe {
+        let init = OleInitialize(null_mut());
+        if init < 0 {
+            return Err(format!("OleInitialize failed: 0x{init:08x}"));
+        }
+
+        let data = Box::into_raw(Box::new(DataObject {
+            vtbl: &DATA_OBJECT_VTBL,
+            refs: AtomicU32::new(1),
+            source,
+            format,
+            rendered: Mutex::new(None),
+        }));
+        let drop_source = Box::into_raw(Box::new(DropSource {
+            vtbl: &DROP_SOURCE_VTBL,
+            refs: AtomicU32::new(1),
+        }));
+
+        // Copy only: a move would let the target delete a passed-through original.
+        let mut effect = DROPEFFECT_NONE;
+        let hr = DoDragDrop(data.cast(), drop_source.cast(), DROPEFFECT_COPY, &mut effect);
+        let rendered = (*data).rendered.lock().unwrap_or_else(|e| e.into_inner()).take();
+        data_release(data);
+        drop_source_release(drop_source);
+        OleUninitialize();
+
+        match (hr, rendered) {
+            (_, Some(Err(e))) => Err(e),
+            (DRAGDROP_S_DROP, Some(Ok(export))) if effect != DROPEFFECT_NONE => Ok(Some(export)),
+            (hr, _) if hr < 0 => Err(format!("DoDragDrop failed: 0x{hr:08x}")),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[repr(C)]
+struct DataObjectVtbl {
+    query_interface: unsafe extern "system" fn(*mut DataObject, *const Guid, *mut *mut c_void) -> Hresult,
+    add_ref: unsafe extern "system" fn(*mut DataObject) -> u32,
+    release: unsafe extern "system" fn(*mut DataObject) -> u32,
+    get_data: unsafe extern "system" fn(*mut DataObject, *const FormatEtc, *mut StgMedium) -> Hresult,
+    get_data_here: unsafe extern "system" fn(*mut DataObject, *const FormatEtc, *mut StgMedium) -> Hresult,
+    query_get_data: unsafe extern "system" fn(*mut DataObject, *const FormatEtc) -> Hresult,
+    get_canonical_format_etc: unsafe extern "system" fn(*mut DataObject, *const FormatEtc, *mut FormatEtc) -> Hresult,
+    set_data: unsafe extern "system" fn(*mut DataObject, *const FormatEtc, *const StgMedium, i32) -> Hresult,
+    enum_format_etc: unsafe extern "system" fn(*mut DataObject, u32, *mut *mut c_void) -> Hresult,
+    d_advise: unsafe extern "system" fn(*mut DataObject, *const FormatEtc, u32, *mut c_void, *mut u32) -> Hresult,
+    d_unadvise: unsafe extern "system" fn(*mut DataObject, u32) -> Hresult,
+    enum_d_advise: unsafe extern "system" fn(*mut DataObject, *mut *mut c_void) -> Hresult,
+}
+
+#[repr(C)]
+struct DataObject {
+    vtbl: *const DataObjectVtbl,
+    refs: AtomicU32,
+    source: PathBuf,
+    format: Option<&'static str>,
+    /// Filled by the first `GetData`; later requests hand out the same file.
+    rendered: Mutex<Option<Result<DragExport, String>>>,
+}
+
+static DATA_OBJECT_VTBL: DataObjectVtbl = DataObjectVtbl {
+    query_interface: data_query_interface,
+    add_ref: data_add_ref,
+    release: data_release,
+    get_data: data_get_data,
+    get_data_here: data_get_data_here,
+    query_get_data: data_query_get_data,
+    get_canonical_format_etc: data_get_canonical_format_etc,
+    set_data: data_set_data,
+    enum_format_etc: data_enum_format_etc,
+    d_advise: data_d_advise,
+    d_unadvise: data_d_unadvise,
+    enum_d_advise: data_enum_d_advise,
+};
+
+fn offers(format: &FormatEtc) -> bool {
+    format.cf_format == CF_HDROP && format.aspect == DVASPECT_CONTENT && format.tymed & TYMED_HGLOBAL != 0
+}
+
+unsafe extern "system" fn data_query_interface(
+    this: *mut DataObject,
+    iid: *const Guid,
+    out: *mut *mut c_void,
+) -> Hresult {
+    if *iid == IID_IUNKNOWN || *iid == IID_IDATAOBJECT {
+        data_add_ref(this);
+        *out = this.cast();
+        S_OK
+    } else {
+        *out = null_mut();
+        E_NOINTERFACE
+    }
+}
+
+unsafe extern "system" fn data_add_ref(this: *mut DataObject) -> u32 {
+    (*this).refs.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+unsafe extern "system" fn data_release(this: *mut DataObject) -> u32 {
+    let left = (*this).refs.fetch_sub(1, Ordering::Release) - 1;
+    if left == 0 {
+        fence(Ordering::Acquire);
+        drop(Box::from_raw(this));
+    }
+    left
+}
+
+unsafe extern "system" fn data_get_data(
+    this: *mut DataObject,
+    format: *const FormatEtc,
+    medium: *mut StgMedium,
+) -> Hresult {
+    if !offers(&*format) {
+        return DV_E_FORMATETC;
+    }
+    let object = &*this;
+    let mut rendered = object.rendered.lock().unwrap_or_else(|e| e.into_inner());
+    let export = rendered.get_or_insert_with(|| export_for_drag(&object.source, object.format));
+    let Ok(export) = export else {
+        return E_FAIL;
+    };
+
+    let handle = hdrop_for(Path::new(&export.path));
+    if handle.is_null() {
+        return E_OUTOFMEMORY;
+    }
+    // The receiver owns and frees the HGLOBAL (no pUnkForRelease).
+    *medium = StgMedium {
+        tymed: TYMED_HGLOBAL,
+        handle,
+        unk_for_release: null_mut(),
+    };
+    S_OK
+}
+
+unsafe extern "system" fn data_get_data_here(_: *mut DataObject, _: *const FormatEtc, _: *mut StgMedium) -> Hresult {
+    E_NOTIMPL
+}
+
+/// Answers format queries without converting; targets probe this on drag-over.
+unsafe extern "system" fn data_query_get_data(_: *mut DataObject, format: *const FormatEtc) -> Hresult {
+    if offers(&*format) {
+        S_OK
+    } else {
+        DV_E_FORMATETC
+    }
+}
+
+unsafe extern "system" fn data_get_canonical_format_etc(
+    _: *mut DataObject,
+    _: *const FormatEtc,
+    out: *mut FormatEtc,
+) -> Hresult {
+    (*out).ptd = null_mut();
+    DATA_S_SAMEFORMATETC
+}
+
+unsafe extern "system" fn data_set_data(_: *mut DataObject, _: *const FormatEtc, _: *const StgMedium, _: i32) -> Hresult {
+    E_NOTIMPL
+}
+
+unsafe extern "system" fn data_enum_format_etc(_: *mut DataObject, direction: u32, out: *mut *mut c_void) -> Hresult {
+    if direction == DATADIR_GET {
+        SHCreateStdEnumFmtEtc(1, &HDROP_FORMAT, out)
+    } else {
+        *out = null_mut();
+        E_NOTIMPL
+    }
+}
+
+unsafe extern "system" fn data_d_advise(
+    _: *mut DataObject,
+    _: *const FormatEtc,
+    _: u32,
+    _: *mut c_void,
+    _: *mut u32,
+) -> Hresult {
+    OLE_E_ADVISENOTSUPPORTED
+}
+
+unsafe extern "system" fn data_d_unadvise(_: *mut DataObject, _: u32) -> Hresult {
+    OLE_E_ADVISENOTSUPPORTED
+}
+
+unsafe extern "system" fn data_enum_d_advise(_: *mut DataObject, _: *mut *mut c_void) -> Hresult {
+    OLE_E_ADVISENOTSUPPORTED
+}
+
+/// Builds a moveable `DROPFILES` block listing `path`.
+fn hdrop_for(path: &Path) -> *mut c_void {
+    let mut wide: Vec<u16> = path.as_os_str().encode_wide().collect();
+    wide.extend([0, 0]); // end of this path, then end of the list
+    let header = std::mem::size_of::<DropFiles>();
+
+    unsafe {
+        let handle = GlobalAlloc(GMEM_MOVEABLE, header + wide.len() * 2);
+        if handle.is_null() {
+            return handle;
+        }
+        let data = GlobalLock(handle);
+        if data.is_null() {
+            GlobalFree(handle);
+            return null_mut();
+        }
+        data.cast::<DropFiles>().write(DropFiles {
+            files_offset: header as u32,
+            x: 0,
+            y: 0,
+            non_client: 0,
+            wide: 1,
+        });
+        std::ptr::copy_nonoverlapping(wide.as_ptr(), data.cast::<u8>().add(header).cast::<u16>(), wide.len());
+        GlobalUnlock(handle);
+        handle
+    }
+}
+
+#[repr(C)]
+struct DropSourceVtbl {
+    query_interface: unsafe extern "system" fn(*mut DropSource, *const Guid, *mut *mut c_void) -> Hresult,
+    add_ref: unsafe extern "system" fn(*mut DropSource) -> u32,
+    release: unsafe extern "system" fn(*mut DropSource) -> u32,
+    query_continue_drag: unsafe extern "system" fn(*mut DropSource, i32, u32) -> Hresult,
+    give_feedback: unsafe extern "system" fn(*mut DropSource, u32) -> Hresult,
+}
+
+#[repr(C)]
+struct DropSource {
+    vtbl: *const DropSourceVtbl,
+    refs: AtomicU32,
+}
+
+static DROP_SOURCE_VTBL: DropSourceVtbl = DropSourceVtbl {
+    query_interface: drop_source_query_interface,
+    add_ref: drop_source_add_ref,
+    release: drop_source_release,
+    query_continue_drag: drop_source_query_continue_drag,
+    give_feedback: drop_source_give_feedback,
+};
+
+unsafe extern "system" fn drop_source_query_interface(
+    this: *mut DropSource,
+    iid: *const Guid,
+    out: *mut *mut c_void,
+) -> Hresult {
+    if *iid == IID_IUNKNOWN || *iid == IID_IDROPSOURCE {
+        drop_source_add_ref(this);
+        *out = this.cast();
+        S_OK
+    } else {
+        *out = null_mut();
+        E_NOINTERFACE
+    }
+}
+
+unsafe extern "system" fn drop_source_add_ref(this: *mut DropSource) -> u32 {
+    (*this).refs.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+unsafe extern "system" fn drop_source_release(this: *mut DropSource) -> u32 {
+    let left = (*this).refs.fetch_sub(1, Ordering::Release) - 1;
+    if left == 0 {
+        fence(Ordering::Acquire);
+        drop(Box::from_raw(this));
+    }
+    left
+}
+
+/// Drops when the button that started the drag is released, cancels on Escape.
+unsafe extern "system" fn drop_source_query_continue_drag(_: *mut DropSource, escape: i32, keys: u32) -> Hresult {
+    if escape != 0 {
+        DRAGDROP_S_CANCEL
+    } else if keys & (MK_LBUTTON | MK_RBUTTON) == 0 {
+        DRAGDROP_S_DROP
+    } else {
+        S_OK
+    }
+}
+
+unsafe extern "system" fn drop_source_give_feedback(_: *mut DropSource, _: u32) -> Hresult {
+    DRAGDROP_S_USEDEFAULTCURSORS
+}
@@ -1,9 +1,39 @@
 use base64::Engine;
-use serde::Serialize;
-use std::io::BufReader;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufReader, Read, Seek};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
 
+#[cfg(windows)]
+mod drag_windows;
+
+/// Most animation frames returned by one `open_image` call; page through longer
+/// animations with `frame_range`.
 const MAX_ANIM_FRAMES: usize = 300;
+/// Default cap on the base64 payload `open_image` will send over IPC; the webview
+/// struggles well before the hundreds-of-MB JSON strings large images produce.
+const DEFAULT_MAX_PAYLOAD_BYTES: u64 = 256 * 1024 * 1024;
+/// Foreground slots kept free of background work unless reconfigured.
+const DEFAULT_RESERVED_FOREGROUND: usize = 1;
+/// Limits for the manual HEIF grid fallback, which runs on files libheif already
+/// refused; the grid header alone must not be able to request a huge canvas.
+const MAX_HEIF_GRID_TILES: usize = 1024;
+const MAX_HEIF_GRID_PIXELS: u64 = 128 * 1024 * 1024;
+/// Upper bound on `max_threads`; beyond this extra decode threads only add memory pressure.
+const MAX_DECODE_THREADS: usize = 64;
+/// Directory listings kept around for `diff_directory`; older tokens fall back to a full listing.
+const MAX_DIRECTORY_SNAPSHOTS: usize = 8;
+/// Documents with no decoder of their own. Directory listings include them only
+/// when asked to; `open_image` shows their embedded preview instead.
+const PREVIEW_ONLY_EXTENSIONS: [&str; 7] = ["ai", "eps", "indd", "cdr", "docx", "xlsx", "pptx"];
+/// How much of a non-ZIP document the XMP and magic-byte preview scans read;
+/// Adobe formats keep their metadata and previews near the start.
+const PREVIEW_SCAN_BYTES: u64 = 32 * 1024 * 1024;
+/// Largest ZIP central directory and thumbnail entry `zip_thumbnail` will read.
+const MAX_ZIP_DIRECTORY_BYTES: usize = 16 * 1024 * 1024;
+const MAX_ZIP_THUMBNAIL_BYTES: usize = 16 * 1024 * 1024;
 
 #[cfg(feature = "heif")]
 use libheif_rs::{ColorSpace, HeifContext, RgbChroma};
@@ -19,7 +49,91 @@ struct ImageFrame {
     width: u32,
     height: u32,
     delay_ms: u32,
-    data: String,
+    /// Raw RGBA pixels, base64-encoded only when the response is serialized so
+    /// an oversized payload is rejected before any encoding work.
+    #[serde(serialize_with = "serialize_base64")]
+    data: Vec<u8>,
+    decoded_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matting: Option<EdgeMatting>,
+}
+
+fn serialize_base64<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+/// Length of the padded base64 encoding of `bytes` bytes.
+fn base64_len(bytes: u64) -> u64 {
+    bytes.div_ceil(3) * 4
+}
+
+/// Border colors and transparency info used by the frontend to pick a letterbox
+/// color and decide whether to draw the checkerboard.
+#[derive(Serialize)]
+struct EdgeMatting {
+    top: [u8; 3],
+    right: [u8; 3],
+    bottom: [u8; 3],
+    left: [u8; 3],
+    average: [u8; 3],
+    transparent_edges: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct DecodeOptions {
+    max_size: Option<u32>,
+    edge_matting: bool,
+    #[cfg_attr(not(feature = "jxl"), allow(dead_code))]
+    jxl_color: JxlColorMode,
+    /// Half-open `start..end` window of animation frames to return (GIF only,
+    /// truncated to `MAX_ANIM_FRAMES`).
+    frame_range: Option<(usize, usize)>,
+}
+
+/// Where JXL pixels get converted for display: jxl-oxide's own output as-is, or
+/// the decoder's rendered profile run through the shared lcms ICC -> sRGB step.
+#[derive(Clone, Copy, Default, PartialEq)]
+enum JxlColorMode {
+    #[default]
+    Builtin,
+    Lcms,
+}
+
+impl JxlColorMode {
+    fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value.map(|v| v.to_ascii_lowercase()).as_deref() {
+            None | Some("builtin") => Ok(JxlColorMode::Builtin),
+            Some("lcms") if cfg!(feature = "lcms") => Ok(JxlColorMode::Lcms),
+            Some("lcms") => Err("lcms 색상 관리를 빌드 옵션 lcms로 활성화하세요".into()),
+            Some(other) => Err(format!("unknown jxl color mode: {other}")),
+        }
+    }
+}
+
+/// Optional `open_image` settings, passed from the frontend as one `options`
+/// object with camelCase keys; anything omitted keeps its default.
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct OpenImageOptions {
+    edge_matting: bool,
+    /// `builtin` (default) or `lcms`.
+    jxl_color: Option<String>,
+    /// Defaults to `DEFAULT_MAX_PAYLOAD_BYTES`; 0 disables the check.
+    max_payload_bytes: Option<u64>,
+    frame_range: Option<(usize, usize)>,
+    /// `foreground` (default) or `background`.
+    priority: Option<String>,
+}
+
+impl OpenImageOptions {
+    fn decode_options(&self, max_size: Option<u32>) -> Result<DecodeOptions, String> {
+        Ok(DecodeOptions {
+            max_size,
+            edge_matting: self.edge_matting,
+            jxl_color: JxlColorMode::parse(self.jxl_color.as_deref())?,
+            frame_range: self.frame_range,
+        })
+    }
 }
 
 #[derive(Serialize)]
@@ -27,11 +141,311 @@ struct ImageResponse {
     path: String,
     format: String,
     frames: Vec<ImageFrame>,
+    /// Index of `frames[0]` within the whole animation.
+    first_frame: usize,
+    /// Frames in the whole file, so callers can tell a truncated window from the end.
+    total_frames: usize,
+    payload_bytes: u64,
+    /// Set when `format` is `preview`: where the embedded preview of a document
+    /// came from, as in `EmbeddedPreview::source`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview_source: Option<String>,
+}
+
+/// Errors from `open_image`. Plain failures still serialize as a bare string;
+/// payload rejections carry enough detail for the frontend to retry smaller.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OpenImageError {
+    Message(String),
+    PayloadTooLarge {
+        code: &'static str,
+        payload_bytes: u64,
+        limit_bytes: u64,
+        suggested_max_size: u32,
+    },
+}
+
+impl From<String> for OpenImageError {
+    fn from(message: String) -> Self {
+        OpenImageError::Message(message)
+    }
+}
+
+impl From<&str> for OpenImageError {
+    fn from(message: &str) -> Self {
+        OpenImageError::Message(message.to_string())
+    }
 }
 
 #[derive(Serialize)]
 struct DirectoryImages {
     images: Vec<String>,
+    token: u64,
+}
+
+#[derive(Serialize)]
+struct DirectoryDiff {
+    token: u64,
+    /// True when `since_token` was unknown or expired; `added` then holds the full listing.
+    full: bool,
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<String>,
+}
+
+/// Size and mtime used to detect changed entries between listings.
+#[derive(Clone, Copy, PartialEq)]
+struct EntryStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+struct DirectorySnapshot {
+    token: u64,
+    dir: PathBuf,
+    include_documents: bool,
+    entries: Arc<HashMap<String, EntryStamp>>,
+}
+
+#[derive(Default)]
+struct SnapshotStore {
+    next_token: u64,
+    snapshots: VecDeque<DirectorySnapshot>,
+}
+
+/// Recent directory listings, managed as Tauri state.
+#[derive(Default)]
+struct DirectorySnapshots(Mutex<SnapshotStore>);
+
+impl DirectorySnapshots {
+    fn insert(&self, dir: PathBuf, include_documents: bool, entries: HashMap<String, EntryStamp>) -> u64 {
+        let mut store = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        store.next_token += 1;
+        let token = store.next_token;
+        if store.snapshots.len() >= MAX_DIRECTORY_SNAPSHOTS {
+            store.snapshots.pop_front();
+        }
+        store.snapshots.push_back(DirectorySnapshot {
+            token,
+            dir,
+            include_documents,
+            entries: Arc::new(entries),
+        });
+        token
+    }
+
+    /// Looks up a snapshot without consuming it, so a token stays valid for
+    /// repeated diffs until it ages out; a hit counts as recent use. A listing
+    /// taken with a different `include_documents` never matches.
+    fn get(&self, token: u64, dir: &Path, include_documents: bool) -> Option<Arc<HashMap<String, EntryStamp>>> {
+        let mut store = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let index = store.snapshots.iter().position(|snap| {
+            snap.token == token && snap.dir == dir && snap.include_documents == include_documents
+        })?;
+        let snap = store.snapshots.remove(index)?;
+        let entries = Arc::clone(&snap.entries);
+        store.snapshots.push_back(snap);
+        Some(entries)
+    }
+}
+
+#[derive(Clone, Serialize)]
+struct DragExport {
+    path: String,
+    format: String,
+    converted: bool,
+}
+
+#[derive(Serialize)]
+struct MotionVideo {
+    path: String,
+    offset: u64,
+    size: u64,
+    duration_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct EmbeddedPreview {
+    path: String,
+    /// Where the preview came from, e.g. `zip:docProps/thumbnail.jpeg`, `xmp` or `scan:jpeg`.
+    source: String,
+    /// Always true; lets the frontend badge the image as a preview, not a render.
+    is_preview: bool,
+    frame: ImageFrame,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct DecodePoolConfig {
+    max_threads: usize,
+    /// Threads background work may never occupy, so the visible image always gets a core.
+    reserved_foreground: usize,
+    background_low_priority: bool,
+}
+
+impl Default for DecodePoolConfig {
+    fn default() -> Self {
+        let cores = std::thread::available_parallelism().map_or(4, |n| n.get());
+        DecodePoolConfig {
+            max_threads: cores,
+            reserved_foreground: DEFAULT_RESERVED_FOREGROUND,
+            background_low_priority: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+enum DecodePriority {
+    /// The image the user is looking at (and anything they explicitly triggered).
+    #[default]
+    Foreground,
+    /// Thumbnailing, preloading, indexing.
+    Background,
+}
+
+impl DecodePriority {
+    fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value.map(|v| v.to_ascii_lowercase()).as_deref() {
+            None | Some("foreground") => Ok(DecodePriority::Foreground),
+            Some("background") => Ok(DecodePriority::Background),
+            Some(other) => Err(format!("unknown decode priority: {other}")),
+        }
+    }
+}
+
+struct DecodeWorkers {
+    config: DecodePoolConfig,
+    /// `max_threads` threads for the image the user is looking at.
+    foreground: rayon::ThreadPool,
+    /// `max_threads - reserved_foreground` threads, optionally low priority.
+    background: rayon::ThreadPool,
+}
+
+/// The decode thread pools, managed as Tauri state. Background jobs run on
+/// their own pool, so rayon work inside a decoder (e.g. RAW demosaic) stays
+/// there too and never steals the foreground threads; at most
+/// `max_threads - reserved_foreground` cores are ever busy with it.
+struct DecodePool(RwLock<Arc<DecodeWorkers>>);
+
+impl DecodePool {
+    fn new(config: DecodePoolConfig) -> Result<Self, String> {
+        Ok(DecodePool(RwLock::new(Arc::new(build_decode_workers(config)?))))
+    }
+
+    fn workers(&self) -> Arc<DecodeWorkers> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Swaps in a freshly built pool; in-flight tasks finish on the old one.
+    fn reconfigure(&self, config: DecodePoolConfig) -> Result<DecodePoolConfig, String> {
+        let workers = build_decode_workers(config)?;
+        let applied = workers.config;
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(workers);
+        Ok(applied)
+    }
+}
+
+fn build_decode_workers(config: DecodePoolConfig) -> Result<DecodeWorkers, String> {
+    let max_threads = config.max_threads.clamp(1, MAX_DECODE_THREADS);
+    let reserved = config.reserved_foreground.min(max_threads - 1);
+    let low_priority = config.background_low_priority;
+
+    // Without a panic handler rayon aborts on a panicking job; dropping the
+    // job's result sender is enough to report it to the waiting command instead.
+    let foreground = rayon::ThreadPoolBuilder::new()
+        .num_threads(max_threads)
+        .thread_name(|i| format!("yupic-decode-{i}"))
+        .panic_handler(|_| {})
+        .build()
+        .map_err(|e| format!("failed to build decode pool: {e}"))?;
+    let background = rayon::ThreadPoolBuilder::new()
+        .num_threads(max_threads - reserved)
+        .thread_name(|i| format!("yupic-decode-bg-{i}"))
+        .start_handler(move |_| {
+            if low_priority {
+                lower_current_thread_priority();
+            }
+        })
+        .panic_handler(|_| {})
+        .build()
+        .map_err(|e| format!("failed to build background decode pool: {e}"))?;
+
+    Ok(DecodeWorkers {
+        config: DecodePoolConfig {
+            max_threads,
+            reserved_foreground: reserved,
+            background_low_priority: low_priority,
+        },
+        foreground,
+        background,
+    })
+}
+
+#[cfg(windows)]
+extern "system" {
+    fn GetCurrentThread() -> *mut std::ffi::c_void;
+    fn SetThreadPriority(thread: *mut std::ffi::c_void, priority: i32) -> i32;
+}
+
+/// Lowers the calling thread's scheduling priority for good; only background
+/// pool threads call this, from their start handler.
+#[cfg(windows)]
+fn lower_current_thread_priority() {
+    const THREAD_PRIORITY_BELOW_NORMAL: i32 = -1;
+    unsafe {
+        SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_BELOW_NORMAL);
+    }
+}
+
+#[cfg(target_os = "macos")]
+extern "C" {
+    fn pthread_set_qos_class_self_np(qos_class: u32, relative_priority: i32) -> i32;
+}
+
+#[cfg(target_os = "macos")]
+fn lower_current_thread_priority() {
+    const QOS_CLASS_UTILITY: u32 = 0x11;
+    unsafe {
+        pthread_set_qos_class_self_np(QOS_CLASS_UTILITY, 0);
+    }
+}
+
+// SCHED_BATCH is milder than nice: batch threads lose wakeup preemption but
+// keep their share of CPU time.
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn sched_setscheduler(pid: i32, policy: i32, param: *const i32) -> i32;
+}
+
+#[cfg(target_os = "linux")]
+fn lower_current_thread_priority() {
+    const SCHED_BATCH: i32 = 3;
+    // pid 0 is the calling thread; sched_param is a single int sched_priority.
+    unsafe {
+        sched_setscheduler(0, SCHED_BATCH, &0);
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
+fn lower_current_thread_priority() {}
+
+/// Runs blocking decode work on the pool for its priority and awaits the
+/// result without tying up an async runtime thread.
+async fn run_decode<T, F>(workers: Arc<DecodeWorkers>, priority: DecodePriority, job: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let threads = match priority {
+        DecodePriority::Foreground => &workers.foreground,
+        DecodePriority::Background => &workers.background,
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    threads.spawn(move || {
+        // The command may have gone away; nothing to do with the result then.
+        let _ = tx.send(job());
+    });
+    rx.await.map_err(|_| "decode task panicked".to_string())
 }
 
 #[derive(Serialize)]
@@ -46,12 +460,103 @@ struct MetadataResponse {
     entries: Vec<MetadataEntry>,
 }
 
+/// Lists the images in a directory. The scan runs on the decode pool so a slow
+/// or network directory never blocks the main thread. Documents from
+/// `PREVIEW_ONLY_EXTENSIONS` are listed only with `include_documents`.
 #[tauri::command]
-fn get_directory_images(path: &str) -> Result<DirectoryImages, String> {
-    let path_buf = PathBuf::from(path);
-    let dir = path_buf.parent().ok_or("no parent directory")?;
-    
-    let mut images = Vec::new();
+async fn get_directory_images(
+    path: String,
+    include_documents: Option<bool>,
+    snapshots: tauri::State<'_, DirectorySnapshots>,
+    pool: tauri::State<'_, DecodePool>,
+) -> Result<DirectoryImages, String> {
+    let dir = resolve_listing_dir(Path::new(&path))?.to_path_buf();
+    let include_documents = include_documents.unwrap_or(false);
+    let scan_dir = dir.clone();
+    let entries = run_decode(pool.workers(), DecodePriority::Foreground, move || {
+        scan_directory_images(&scan_dir, include_documents)
+    })
+    .await??;
+
+    let mut images: Vec<String> = entries.keys().cloned().collect();
+    images.sort();
+
+    let token = snapshots.insert(dir, include_documents, entries);
+    Ok(DirectoryImages { images, token })
+}
+
+/// Returns what was added, removed or modified since the listing identified by
+/// `since_token`, so the frontend can patch its filmstrip after watcher events.
+/// `path` may be the directory itself or a file inside it; `include_documents`
+/// must match the listing the token came from.
+#[tauri::command]
+async fn diff_directory(
+    path: String,
+    since_token: Option<u64>,
+    include_documents: Option<bool>,
+    snapshots: tauri::State<'_, DirectorySnapshots>,
+    pool: tauri::State<'_, DecodePool>,
+) -> Result<DirectoryDiff, String> {
+    let dir = resolve_listing_dir(Path::new(&path))?.to_path_buf();
+    let include_documents = include_documents.unwrap_or(false);
+    let scan_dir = dir.clone();
+    let current = run_decode(pool.workers(), DecodePriority::Foreground, move || {
+        scan_directory_images(&scan_dir, include_documents)
+    })
+    .await??;
+
+    let previous = since_token.and_then(|token| snapshots.get(token, &dir, include_documents));
+    let full = previous.is_none();
+    let (added, removed, changed) = diff_entries(&previous.unwrap_or_default(), &current);
+
+    let token = snapshots.insert(dir, include_documents, current);
+    Ok(DirectoryDiff {
+        token,
+        full,
+        added,
+        removed,
+        changed,
+    })
+}
+
+/// Splits two listings into sorted `(added, removed, changed)` paths; an entry
+/// counts as changed when its size or mtime differs.
+fn diff_entries(
+    previous: &HashMap<String, EntryStamp>,
+    current: &HashMap<String, EntryStamp>,
+) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    for (entry, stamp) in current {
+        match previous.get(entry) {
+            None => added.push(entry.clone()),
+            Some(old) if old != stamp => changed.push(entry.clone()),
+            Some(_) => {}
+        }
+    }
+    let mut removed: Vec<String> = previous
+        .keys()
+        .filter(|entry| !current.contains_key(*entry))
+        .cloned()
+        .collect();
+    added.sort();
+    changed.sort();
+    removed.sort();
+    (added, removed, changed)
+}
+
+/// The directory a listing command works on: `path` itself when it is a
+/// directory, otherwise the directory containing the file.
+fn resolve_listing_dir(path: &Path) -> Result<&Path, String> {
+    if path.is_dir() {
+        Ok(path)
+    } else {
+        path.parent().ok_or_else(|| "no parent directory".to_string())
+    }
+}
+
+fn scan_directory_images(dir: &Path, include_documents: bool) -> Result<HashMap<String, EntryStamp>, String> {
+    let mut images = HashMap::new();
     let entries = std::fs::read_dir(dir)
         .map_err(|e| format!("failed to read directory: {e}"))?;
     
@@ -65,12 +570,22 @@ fn get_directory_images(path: &str) -> Result<DirectoryImages, String> {
     
     for entry in entries.flatten() {
         let entry_path = entry.path();
-        if entry_path.is_file() {
+        let Ok(meta) = std::fs::metadata(&entry_path) else {
+            continue;
+        };
+        if meta.is_file() {
             if let Some(ext) = entry_path.extension() {
                 if let Some(ext_str) = ext.to_str() {
-                    if extensions.contains(&ext_str.to_ascii_lowercase().as_str()) {
+                    let ext = ext_str.to_ascii_lowercase();
+                    if extensions.contains(&ext.as_str())
+                        || (include_documents && PREVIEW_ONLY_EXTENSIONS.contains(&ext.as_str()))
+                    {
                         if let Some(path_str) = entry_path.to_str() {
-                            images.push(path_str.to_string());
+                            let stamp = EntryStamp {
+                                len: meta.len(),
+                                modified: meta.modified().ok(),
+                            };
+                            images.insert(path_str.to_string(), stamp);
                         }
                     }
                 }
@@ -78,8 +593,25 @@ fn get_directory_images(path: &str) -> Result<DirectoryImages, String> {
         }
     }
     
-    images.sort();
-    Ok(DirectoryImages { images })
+    Ok(images)
+}
+
+/// Updates the decode pool settings (omitted fields keep their current value)
+/// and returns the configuration actually applied, with `max_threads` clamped
+/// to `1..=MAX_DECODE_THREADS`.
+#[tauri::command]
+fn configure_decode_pool(
+    max_threads: Option<usize>,
+    reserved_foreground: Option<usize>,
+    background_low_priority: Option<bool>,
+    pool: tauri::State<'_, DecodePool>,
+) -> Result<DecodePoolConfig, String> {
+    let current = pool.workers().config;
+    pool.reconfigure(DecodePoolConfig {
+        max_threads: max_threads.unwrap_or(current.max_threads),
+        reserved_foreground: reserved_foreground.unwrap_or(current.reserved_foreground),
+        background_low_priority: background_low_priority.unwrap_or(current.background_low_priority),
+    })
 }
 
 #[tauri::command]
@@ -106,30 +638,60 @@ fn get_metadata(path: &str) -> Result<MetadataResponse, String> {
 }
 
 #[tauri::command]
-async fn open_image(path: String, max_size: Option<u32>) -> Result<ImageResponse, String> {
+async fn open_image(
+    path: String,
+    max_size: Option<u32>,
+    options: Option<OpenImageOptions>,
+    pool: tauri::State<'_, DecodePool>,
+) -> Result<ImageResponse, OpenImageError> {
     // Force rebuild for feature flags
     let path_buf = PathBuf::from(path);
     if !path_buf.exists() {
         return Err("file not found".into());
     }
 
-    tauri::async_runtime::spawn_blocking(move || {
-        let ext = path_buf
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("")
-            .to_ascii_lowercase();
+    let options = options.unwrap_or_default();
+    let opts = options.decode_options(max_size)?;
+    let ext = path_buf
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    if let Some((start, end)) = opts.frame_range {
+        if start >= end {
+            return Err(format!("invalid frame range: {start}..{end}").into());
+        }
+        if ext != "gif" {
+            return Err(format!("frame_range is only supported for animated gif, not .{ext}").into());
+        }
+    }
+
+    let limit_bytes = options.max_payload_bytes.unwrap_or(DEFAULT_MAX_PAYLOAD_BYTES);
+    let priority = DecodePriority::parse(options.priority.as_deref())?;
 
+    run_decode(pool.workers(), priority, move || -> Result<ImageResponse, OpenImageError> {
+        let mut total_frames = None;
+        let mut preview_source = None;
         let (frames, format) = match ext.as_str() {
-            "gif" => decode_gif(&path_buf, max_size)?,
+            "gif" => {
+                let file = std::fs::File::open(&path_buf)
+                    .map_err(|err| format!("failed to open gif: {err}"))?;
+                let total = gif_frame_count(BufReader::new(file))?;
+                let first = opts.frame_range.map_or(0, |(start, _)| start);
+                if first >= total {
+                    return Err(format!("frame range starts at {first} but gif has {total} frames").into());
+                }
+                total_frames = Some(total);
+                decode_gif(&path_buf, opts)?
+            }
             "avif" => {
-                let (frame, fmt) = decode_static_image(&path_buf, max_size)?;
+                let (frame, fmt) = decode_static_image(&path_buf, opts)?;
                 (vec![frame], fmt)
             }
             "heic" | "heif" => {
                 #[cfg(feature = "heif")]
                 {
-                    decode_heif(&path_buf, max_size)?
+                    decode_heif(&path_buf, opts)?
                 }
                 #[cfg(not(feature = "heif"))]
                 {
@@ -139,7 +701,7 @@ async fn open_image(path: String, max_size: Option<u32>) -> Result<ImageResponse
             "jxl" => {
                 #[cfg(feature = "jxl")]
                 {
-                    decode_jxl(&path_buf, max_size)?
+                    decode_jxl(&path_buf, opts)?
                 }
                 #[cfg(not(feature = "jxl"))]
                 {
@@ -149,15 +711,20 @@ async fn open_image(path: String, max_size: Option<u32>) -> Result<ImageResponse
             "dng" | "cr2" | "crw" | "nef" | "nrw" | "orf" | "rw2" | "pef" | "sr2" | "arw" | "raw" | "raf" => {
                 #[cfg(feature = "raw")]
                 {
-                    decode_raw(&path_buf, max_size)?
+                    decode_raw(&path_buf, opts)?
                 }
                 #[cfg(not(feature = "raw"))]
                 {
                     return Err("RAW 기능이 활성화되지 않았습니다. 서버를 재시작해주세요.".into());
                 }
             }
+            other if PREVIEW_ONLY_EXTENSIONS.contains(&other) => {
+                let (img, source) = load_embedded_preview(&path_buf)?;
+                preview_source = Some(source);
+                (vec![encode_frame(img, opts, 0)], "preview".into())
+            }
             _ => {
-                let (frame, fmt) = decode_static_image(&path_buf, max_size)?;
+                let (frame, fmt) = decode_static_image(&path_buf, opts)?;
                 (vec![frame], fmt)
             }
         };
@@ -166,177 +733,1484 @@ async fn open_image(path: String, max_size: Option<u32>) -> Result<ImageResponse
             return Err("no frames decoded".into());
         }
 
+        let payload_bytes = check_payload_limit(&frames, limit_bytes)?;
+
         Ok(ImageResponse {
             path: path_buf.display().to_string(),
             format,
+            first_frame: opts.frame_range.map_or(0, |(start, _)| start),
+            total_frames: total_frames.unwrap_or(frames.len()),
             frames,
+            payload_bytes,
+            preview_source,
         })
     })
-    .await
-    .map_err(|e| format!("Task failed: {}", e))?
+    .await?
 }
 
-fn resize_if_needed(img: image::DynamicImage, max_size: Option<u32>) -> image::DynamicImage {
-    if let Some(max) = max_size {
-        if max > 0 && (img.width() > max || img.height() > max) {
-            // Use Nearest for very large images (>8MP), Triangle otherwise
-            let pixels = img.width() as u64 * img.height() as u64;
-            let filter = if pixels > 8_000_000 {
-                image::imageops::FilterType::Nearest
-            } else {
-                image::imageops::FilterType::Triangle
-            };
-            return img.resize(max, max, filter);
-        }
+/// Returns the serialized frame payload size, or `PayloadTooLarge` with a
+/// `max_size` that should fit when it exceeds `limit_bytes` (0 disables the check).
+fn check_payload_limit(frames: &[ImageFrame], limit_bytes: u64) -> Result<u64, OpenImageError> {
+    let payload_bytes: u64 = frames.iter().map(|f| base64_len(f.decoded_bytes)).sum();
+    if limit_bytes > 0 && payload_bytes > limit_bytes {
+        // Payload scales with pixel count, so shrink the longest side by the
+        // square root of the overshoot.
+        let longest = frames.iter().map(|f| f.width.max(f.height)).max().unwrap_or(0);
+        let scale = (limit_bytes as f64 / payload_bytes as f64).sqrt();
+        return Err(OpenImageError::PayloadTooLarge {
+            code: "payload_too_large",
+            payload_bytes,
+            limit_bytes,
+            suggested_max_size: ((longest as f64 * scale).floor() as u32).max(1),
+        });
     }
-    img
+    Ok(payload_bytes)
 }
 
-fn decode_static_image(path: &Path, max_size: Option<u32>) -> Result<(ImageFrame, String), String> {
-    let mut reader = image::io::Reader::open(path)
-        .map_err(|err| format!("failed to open file {}: {err}", path.display()))?;
-    
-    // image 0.24 uses set_limits or similar? Actually Reader has no_limits() in some versions.
-    // Let's use the standard 0.24 way if no_limits() is missing.
-    reader.no_limits();
-    
-    let reader = reader.with_guessed_format()
-        .map_err(|err| format!("failed to guess format for {}: {err}", path.display()))?;
+/// Extracts the MP4 embedded in a Samsung/Google motion photo to `dest`.
+#[tauri::command]
+async fn extract_motion_video(
+    path: String,
+    dest: String,
+    pool: tauri::State<'_, DecodePool>,
+) -> Result<MotionVideo, String> {
+    let path_buf = PathBuf::from(path);
+    if !path_buf.exists() {
+        return Err("file not found".into());
+    }
 
-    let format = reader
-        .format()
-        .map(|fmt| format!("{fmt:?}"))
-        .unwrap_or_else(|| "unknown".into());
+    if is_same_file(&path_buf, Path::new(&dest)) {
+        return Err("motion video destination must differ from the source photo".into());
+    }
 
-    let decoded = reader
-        .decode()
-        .map_err(|err| format!("failed to decode image {}: {err}", path.display()))?;
-    
-    let resized = resize_if_needed(decoded, max_size);
-    let rgba = resized.to_rgba8();
-    let width = rgba.width();
-    let height = rgba.height();
-    let data = base64::engine::general_purpose::STANDARD.encode(rgba.into_raw());
+    run_decode(pool.workers(), DecodePriority::Foreground, move || {
+        let bytes = std::fs::read(&path_buf)
+            .map_err(|e| format!("failed to read motion photo: {e}"))?;
+        let offset = find_motion_video_offset(&bytes)
+            .ok_or_else(|| "no embedded motion video found".to_string())?;
+        let video = &bytes[offset..];
 
-    Ok((
-        ImageFrame {
-            width,
-            height,
-            delay_ms: 0,
-            data,
-        },
-        format,
-    ))
+        std::fs::write(&dest, video).map_err(|e| format!("failed to write motion video: {e}"))?;
+
+        Ok(MotionVideo {
+            path: dest,
+            offset: offset as u64,
+            size: video.len() as u64,
+            duration_ms: mp4_duration_ms(video),
+        })
+    })
+    .await?
 }
 
-fn decode_gif(path: &Path, max_size: Option<u32>) -> Result<(Vec<ImageFrame>, String), String> {
-    use image::codecs::gif::GifDecoder;
-    use image::AnimationDecoder;
-    use std::fs::File;
+/// Compares canonical paths; `b` may not exist yet, in which case its parent is resolved.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    let canonical = |p: &Path| {
+        p.canonicalize().ok().or_else(|| {
+            let parent = match p.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            Some(parent.canonicalize().ok()?.join(p.file_name()?))
+        })
+    };
+    matches!((canonical(a), canonical(b)), (Some(x), Some(y)) if x == y)
+}
 
-    let file = File::open(path).map_err(|err| format!("failed to open gif: {err}"))?;
-    let reader = BufReader::new(file);
-    let decoder = GifDecoder::new(reader).map_err(|err| format!("failed to read gif: {err}"))?;
-    let frames = decoder
-        .into_frames()
-        .collect_frames()
-        .map_err(|err| format!("failed to collect gif frames: {err}"))?;
+/// Major brands accepted for an embedded `ftyp` box (prefix match).
+const MOTION_VIDEO_BRANDS: [&[u8]; 5] = [b"mp4", b"iso", b"qt  ", b"avc1", b"M4V"];
 
-    let capped = if frames.len() > MAX_ANIM_FRAMES {
-        frames.into_iter().take(MAX_ANIM_FRAMES).collect()
-    } else {
-        frames
-    };
+/// Locates the start of the embedded MP4, preferring the offsets declared in XMP
+/// (Google `MicroVideoOffset` / `Container:Directory`), then Samsung's
+/// `MotionPhoto_Data` trailer marker, then an `ftyp` scan after the JPEG's EOI.
+fn find_motion_video_offset(bytes: &[u8]) -> Option<usize> {
+    let xmp_lengths = [
+        xmp_attr_u64(bytes, "GCamera:MicroVideoOffset"),
+        motion_photo_item_length(bytes),
+    ];
+    for len in xmp_lengths.into_iter().flatten() {
+        if let Some(start) = bytes.len().checked_sub(len as usize) {
+            if looks_like_mp4(bytes, start) {
+                return Some(start);
+            }
+        }
+    }
 
-    let mut out = Vec::with_capacity(capped.len());
-    for frame in capped {
-        let delay: image::Delay = frame.delay();
-        let (num, denom) = delay.numer_denom_ms();
-        let delay_ms = if denom == 0 {
-            num
-        } else {
-            let ms = (num as f32 / denom as f32).round() as u32;
-            ms.max(10)
-        };
+    const SAMSUNG_MARKER: &[u8] = b"MotionPhoto_Data";
+    if let Some(pos) = find_bytes(bytes, SAMSUNG_MARKER, 0) {
+        let start = pos + SAMSUNG_MARKER.len();
+        if looks_like_mp4(bytes, start) {
+            return Some(start);
+        }
+    }
 
-        let buffer = frame.into_buffer();
-        let dynamic = image::DynamicImage::ImageRgba8(buffer);
-        let resized = resize_if_needed(dynamic, max_size);
-        let rgba = resized.to_rgba8();
-        
-        let width = rgba.width();
-        let height = rgba.height();
-        let data = base64::engine::general_purpose::STANDARD.encode(rgba.into_raw());
-
-        out.push(ImageFrame {
-            width,
-            height,
-            delay_ms,
-            data,
-        });
+    // Compressed JPEG data can contain `ftyp` by chance, so only look past EOI.
+    let mut from = jpeg_end(bytes)?;
+    while let Some(pos) = find_bytes(bytes, b"ftyp", from) {
+        if pos >= 4 && looks_like_mp4(bytes, pos - 4) {
+            return Some(pos - 4);
+        }
+        from = pos + 4;
     }
+    None
+}
 
-    Ok((out, "gif".into()))
+/// Checks for a plausible `ftyp` box at `start`: sane size that fits the file
+/// and a known MP4/QuickTime major brand.
+fn looks_like_mp4(bytes: &[u8], start: usize) -> bool {
+    let Some(header) = bytes.get(start..start.saturating_add(12)) else {
+        return false;
+    };
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    &header[4..8] == b"ftyp"
+        && (16..=1024).contains(&size)
+        && size & 3 == 0
+        && start + size <= bytes.len()
+        && MOTION_VIDEO_BRANDS.iter().any(|brand| header[8..12].starts_with(brand))
 }
 
-#[cfg(feature = "heif")]
-fn decode_heif(_path: &Path, max_size: Option<u32>) -> Result<(Vec<ImageFrame>, String), String> {
-    use libheif_rs::LibHeif;
-    
-    let path_str = _path
-        .to_str()
-        .ok_or_else(|| "invalid heif path".to_string())?;
+/// Walks the JPEG marker structure and returns the offset just past EOI, so
+/// `FF D9` inside APP segments (e.g. EXIF thumbnails) isn't mistaken for the end.
+fn jpeg_end(bytes: &[u8]) -> Option<usize> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    loop {
+        if *bytes.get(pos)? != 0xFF {
+            return None;
+        }
+        // Any number of 0xFF fill bytes may precede a marker.
+        while *bytes.get(pos + 1)? == 0xFF {
+            pos += 1;
+        }
+        let marker = bytes[pos + 1];
+        pos += 2;
+        match marker {
+            0xD9 => return Some(pos),
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {
+                let len = u16::from_be_bytes([*bytes.get(pos)?, *bytes.get(pos + 1)?]) as usize;
+                if len < 2 {
+                    return None;
+                }
+                pos += len;
+                if marker == 0xDA {
+                    // Entropy-coded data ends at the first marker that isn't a
+                    // stuffed 0xFF00 or a restart marker.
+                    loop {
+                        if *bytes.get(pos)? == 0xFF {
+                            let next = *bytes.get(pos + 1)?;
+                            if next != 0x00 && !(0xD0..=0xD7).contains(&next) {
+                                break;
+                            }
+                            pos += 2;
+                        } else {
+                            pos += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
 
-    let lib_heif = LibHeif::new();
-    let ctx = HeifContext::read_from_file(path_str)
+/// Reads `Item:Length` from the `MotionPhoto` entry of an XMP `Container:Directory`.
+fn motion_photo_item_length(bytes: &[u8]) -> Option<u64> {
+    let pos = find_bytes(bytes, b"Item:Semantic=\"MotionPhoto\"", 0)?;
+    // Attributes of the same <Container:Item> element follow within a short span.
+    let end = (pos + 256).min(bytes.len());
+    xmp_attr_u64(&bytes[pos..end], "Item:Length")
+}
+
+fn xmp_attr_u64(bytes: &[u8], name: &str) -> Option<u64> {
+    let needle = format!("{name}=\"");
+    let start = find_bytes(bytes, needle.as_bytes(), 0)? + needle.len();
+    let digits: Vec<u8> = bytes[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .copied()
+        .collect();
+    std::str::from_utf8(&digits).ok()?.parse().ok().filter(|&v| v > 0)
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from >= haystack.len() {
+        return None;
+    }
+    haystack[from..]
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|p| p + from)
+}
+
+/// Reads the movie duration from `moov/mvhd`.
+fn mp4_duration_ms(video: &[u8]) -> Option<u64> {
+    let moov = mp4_find_box(video, b"moov")?;
+    let mvhd = mp4_find_box(moov, b"mvhd")?;
+    let version = *mvhd.first()?;
+    let read_u32 = |at: usize| -> Option<u64> {
+        Some(u32::from_be_bytes(mvhd.get(at..at + 4)?.try_into().ok()?) as u64)
+    };
+    let (timescale, duration) = if version == 1 {
+        let duration = u64::from_be_bytes(mvhd.get(24..32)?.try_into().ok()?);
+        (read_u32(20)?, duration)
+    } else {
+        (read_u32(12)?, read_u32(16)?)
+    };
+    if timescale == 0 {
+        return None;
+    }
+    Some(duration.saturating_mul(1000) / timescale)
+}
+
+/// Returns the payload of the first box of type `kind` directly inside `data`.
+fn mp4_find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    mp4_boxes(data)
+        .into_iter()
+        .find(|(k, _)| k == kind)
+        .map(|(_, payload)| payload)
+}
+
+/// Splits `data` into its sibling ISOBMFF boxes as (type, payload) pairs.
+fn mp4_boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut out = Vec::new();
+    let mut pos = 0usize;
+    while pos + 8 <= data.len() {
+        let size32 = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let (header, size) = match size32 {
+            0 => (8, data.len() - pos),
+            1 => match data.get(pos + 8..pos + 16) {
+                Some(b) => (16, u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as usize),
+                None => break,
+            },
+            n => (8, n),
+        };
+        if size < header {
+            break;
+        }
+        let end = pos.saturating_add(size).min(data.len());
+        let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+        out.push((kind, data.get(pos + header..end).unwrap_or(&[])));
+        pos = end;
+    }
+    out
+}
+
+/// Pulls the embedded preview out of non-image files (AI, EPS, INDD, CDR, Office
+/// documents, ...) so they can be glanced at in the grid. Best effort: tries ZIP
+/// thumbnails, then XMP thumbnails, then the first JPEG/PNG stream in the file.
+#[tauri::command]
+async fn extract_embedded_preview(
+    path: String,
+    max_size: Option<u32>,
+    priority: Option<String>,
+    pool: tauri::State<'_, DecodePool>,
+) -> Result<EmbeddedPreview, String> {
+    let path_buf = PathBuf::from(path);
+    if !path_buf.exists() {
+        return Err("file not found".into());
+    }
+    let priority = DecodePriority::parse(priority.as_deref())?;
+
+    run_decode(pool.workers(), priority, move || {
+        let (img, source) = load_embedded_preview(&path_buf)?;
+
+        let opts = DecodeOptions {
+            max_size,
+            ..DecodeOptions::default()
+        };
+        Ok(EmbeddedPreview {
+            path: path_buf.display().to_string(),
+            source,
+            is_preview: true,
+            frame: encode_frame(img, opts, 0),
+        })
+    })
+    .await?
+}
+
+/// Tries each embedded-preview source in turn. ZIP documents are read through
+/// their central directory; everything else only has its first
+/// `PREVIEW_SCAN_BYTES` searched. ZIPs never get the magic-byte scan, which
+/// would otherwise pick up whatever stored image comes first.
+fn load_embedded_preview(path: &Path) -> Result<(image::DynamicImage, String), String> {
+    use std::io::SeekFrom;
+
+    let mut file = std::fs::File::open(path)
+        .map_err(|e| format!("failed to open file for preview: {e}"))?;
+    if let Some(found) = zip_thumbnail(&mut file) {
+        return Ok(found);
+    }
+
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| format!("failed to read file for preview: {e}"))?;
+    let mut prefix = Vec::new();
+    file.take(PREVIEW_SCAN_BYTES)
+        .read_to_end(&mut prefix)
+        .map_err(|e| format!("failed to read file for preview: {e}"))?;
+
+    let is_zip = prefix.starts_with(b"PK\x03\x04");
+    xmp_thumbnail(&prefix)
+        .or_else(|| (!is_zip).then(|| scan_embedded_image(&prefix)).flatten())
+        .ok_or_else(|| "no embedded preview found".to_string())
+}
+
+/// Finds a `thumbnail`/`preview` JPEG or PNG entry in a ZIP-based document
+/// (OOXML `docProps/`, ODF `Thumbnails/`, CorelDRAW X4+ `previews/`, ...).
+/// Reads only the end-of-central-directory tail, the central directory and the
+/// chosen entry.
+fn zip_thumbnail<R: Read + Seek>(reader: &mut R) -> Option<(image::DynamicImage, String)> {
+    use std::io::SeekFrom;
+
+    let le16 = |b: &[u8], at: usize| -> Option<usize> {
+        Some(u16::from_le_bytes([*b.get(at)?, *b.get(at + 1)?]) as usize)
+    };
+    let le32 = |b: &[u8], at: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(b.get(at..at + 4)?.try_into().ok()?) as usize)
+    };
+    let read_at = |reader: &mut R, offset: u64, len: usize| -> Option<Vec<u8>> {
+        reader.seek(SeekFrom::Start(offset)).ok()?;
+        let mut buf = vec![0u8; len];
+        reader.read_exact(&mut buf).ok()?;
+        Some(buf)
+    };
+
+    let file_len = reader.seek(SeekFrom::End(0)).ok()?;
+    if read_at(reader, 0, 4)? != b"PK\x03\x04" {
+        return None;
+    }
+
+    // The end-of-central-directory record sits within the last 64 KiB (max comment size).
+    let tail_len = file_len.min(22 + u16::MAX as u64);
+    let tail = read_at(reader, file_len - tail_len, tail_len as usize)?;
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&at| tail[at..at + 4] == *b"PK\x05\x06")?;
+    let entries = le16(&tail, eocd + 10)?;
+    let directory_len = le32(&tail, eocd + 12)?;
+    if directory_len > MAX_ZIP_DIRECTORY_BYTES {
+        return None;
+    }
+    let directory = read_at(reader, le32(&tail, eocd + 16)? as u64, directory_len)?;
+
+    let mut best: Option<(usize, String, usize, usize, usize)> = None;
+    let mut at = 0;
+    for _ in 0..entries {
+        if directory.get(at..at + 4)? != b"PK\x01\x02" {
+            break;
+        }
+        let method = le16(&directory, at + 10)?;
+        let compressed = le32(&directory, at + 20)?;
+        let uncompressed = le32(&directory, at + 24)?;
+        let name_len = le16(&directory, at + 28)?;
+        let extra_len = le16(&directory, at + 30)?;
+        let comment_len = le16(&directory, at + 32)?;
+        let local = le32(&directory, at + 42)?;
+        let name = String::from_utf8_lossy(directory.get(at + 46..at + 46 + name_len)?).to_string();
+        at += 46 + name_len + extra_len + comment_len;
+
+        let file = Path::new(&name);
+        let stem = file.file_stem().and_then(|s| s.to_str()).unwrap_or("").to_ascii_lowercase();
+        let ext = file.extension().and_then(|s| s.to_str()).unwrap_or("").to_ascii_lowercase();
+        let is_candidate = (stem == "thumbnail" || stem == "preview")
+            && matches!(ext.as_str(), "jpg" | "jpeg" | "png")
+            && uncompressed <= MAX_ZIP_THUMBNAIL_BYTES;
+        if is_candidate && !matches!(&best, Some(b) if b.0 >= uncompressed) {
+            best = Some((uncompressed, name, method, local, compressed));
+        }
+    }
+
+    let (uncompressed, name, method, local, compressed) = best?;
+    let header = read_at(reader, local as u64, 30)?;
+    if header.get(0..4)? != b"PK\x03\x04" {
+        return None;
+    }
+    let data_start = local + 30 + le16(&header, 26)? + le16(&header, 28)?;
+    reader.seek(SeekFrom::Start(data_start as u64)).ok()?;
+    let raw = reader.take(compressed as u64);
+
+    // Never produce more than the directory promised, whatever the stream says.
+    let mut data = Vec::with_capacity(uncompressed);
+    match method {
+        0 => raw.take(uncompressed as u64).read_to_end(&mut data).ok()?,
+        8 => flate2::read::DeflateDecoder::new(raw)
+            .take(uncompressed as u64)
+            .read_to_end(&mut data)
+            .ok()?,
+        _ => return None,
+    };
+
+    let img = image::load_from_memory(&data).ok()?;
+    Some((img, format!("zip:{name}")))
+}
+
+/// Decodes the base64 JPEG in an XMP `xmpGImg:image` thumbnail, which Adobe apps
+/// (Illustrator, InDesign, EPS exports) write in either element or attribute form.
+fn xmp_thumbnail(bytes: &[u8]) -> Option<(image::DynamicImage, String)> {
+    const TAG: &[u8] = b"xmpGImg:image";
+
+    let mut from = 0;
+    while let Some(pos) = find_bytes(bytes, TAG, from) {
+        from = pos + TAG.len();
+        let (start, terminator) = match bytes.get(from..from + 2)? {
+            [b'>', _] => (from + 1, b'<'),
+            b"=\"" => (from + 2, b'"'),
+            _ => continue,
+        };
+        let len = bytes[start..].iter().position(|&b| b == terminator)?;
+        let text = String::from_utf8_lossy(&bytes[start..start + len])
+            .replace("&#xA;", "")
+            .replace("&#xD;", "");
+        let cleaned: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+        let Ok(data) = base64::engine::general_purpose::STANDARD.decode(cleaned) else {
+            continue;
+        };
+        if let Ok(img) = image::load_from_memory(&data) {
+            return Some((img, "xmp".into()));
+        }
+    }
+    None
+}
+
+/// Last resort: the first standalone JPEG or PNG stream that decodes.
+fn scan_embedded_image(bytes: &[u8]) -> Option<(image::DynamicImage, String)> {
+    const MAX_ATTEMPTS: usize = 16;
+    let candidates: [(&[u8], image::ImageFormat, &str); 2] = [
+        (b"\xFF\xD8\xFF", image::ImageFormat::Jpeg, "scan:jpeg"),
+        (b"\x89PNG\r\n\x1a\n", image::ImageFormat::Png, "scan:png"),
+    ];
+
+    for (magic, format, source) in candidates {
+        let mut from = 0;
+        for _ in 0..MAX_ATTEMPTS {
+            let Some(pos) = find_bytes(bytes, magic, from) else {
+                break;
+            };
+            from = pos + magic.len();
+            if let Ok(img) = image::load_from_memory_with_format(&bytes[pos..], format) {
+                // Skip icons and other tiny embedded bits.
+                if img.width() >= 32 && img.height() >= 32 {
+                    return Some((img, source.into()));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Formats most external apps accept as-is, so drags hand over the original file.
+const DRAG_PASSTHROUGH_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "gif", "bmp", "webp"];
+/// Drag exports older than this are removed from the temp dir on the next export.
+const DRAG_EXPORT_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+/// Upper bound on cached drag exports; the oldest go first.
+const MAX_DRAG_EXPORTS: usize = 32;
+
+/// Materializes a JPEG/PNG copy of `path` for drops into apps that can't read
+/// HEIC/RAW/JXL, ahead of the drag. Results are cached in the temp dir by source
+/// path, mtime and target format. On Windows, prefer `start_drag_export`, which
+/// only converts once a drop target actually asks for the file.
+#[tauri::command]
+async fn prepare_drag_export(
+    path: String,
+    format: Option<String>,
+    pool: tauri::State<'_, DecodePool>,
+) -> Result<DragExport, String> {
+    let path_buf = PathBuf::from(path);
+    if !path_buf.exists() {
+        return Err("file not found".into());
+    }
+    let target = parse_drag_format(format.as_deref())?;
+
+    run_decode(pool.workers(), DecodePriority::Foreground, move || {
+        export_for_drag(&path_buf, target)
+    })
+    .await?
+}
+
+/// Starts an OS drag of `path` whose JPEG/PNG copy is only produced when a drop
+/// target asks for the file (OLE delayed rendering), so cancelled drags and drops
+/// back onto the viewer cost nothing. Resolves when the drag ends, with the file
+/// that was handed over, if any. Windows only; elsewhere call
+/// `prepare_drag_export` before starting the drag.
+#[tauri::command]
+async fn start_drag_export(
+    path: String,
+    format: Option<String>,
+    app: tauri::AppHandle,
+) -> Result<Option<DragExport>, String> {
+    let path_buf = PathBuf::from(path);
+    if !path_buf.exists() {
+        return Err("file not found".into());
+    }
+    let target = parse_drag_format(format.as_deref())?;
+
+    #[cfg(windows)]
+    {
+        // DoDragDrop runs a modal loop and must be called on the UI thread.
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        app.run_on_main_thread(move || {
+            let _ = tx.send(drag_windows::drag_with_delayed_export(path_buf, target));
+        })
+        .map_err(|e| format!("failed to start drag: {e}"))?;
+        rx.await.map_err(|_| "drag was interrupted".to_string())?
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = (path_buf, target, app);
+        Err("delayed-rendering drags are only available on Windows; use prepare_drag_export".into())
+    }
+}
+
+/// Validates an explicit drag export format; `None` lets the image decide.
+fn parse_drag_format(format: Option<&str>) -> Result<Option<&'static str>, String> {
+    match format.map(|f| f.to_ascii_lowercase()).as_deref() {
+        None => Ok(None),
+        Some("png") => Ok(Some("png")),
+        Some("jpg") | Some("jpeg") => Ok(Some("jpg")),
+        Some(other) => Err(format!("unsupported drag export format: {other}")),
+    }
+}
+
+/// Produces the file handed to a drop target: the original for formats other
+/// apps read when no format is forced, otherwise a cached JPEG, or a PNG when
+/// the image has real transparency.
+fn export_for_drag(path: &Path, format: Option<&'static str>) -> Result<DragExport, String> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    if format.is_none() && DRAG_PASSTHROUGH_EXTENSIONS.contains(&ext.as_str()) {
+        return Ok(DragExport {
+            path: path.display().to_string(),
+            format: ext,
+            converted: false,
+        });
+    }
+
+    let img = load_primary_image(path)?;
+    let target = format.unwrap_or(if has_transparent_pixels(&img) { "png" } else { "jpg" });
+
+    let dest = drag_export_path(&drag_export_dir()?, path, target);
+    evict_drag_exports(&dest);
+    if !dest.exists() {
+        // Write to a unique sibling name first so a half-written file is never
+        // dropped and concurrent drags of the same file don't share a temp file.
+        static PART_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let part_id = PART_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let partial = dest.with_extension(format!("{target}.{}-{part_id}.part", std::process::id()));
+        let file = std::fs::File::create(&partial)
+            .map_err(|e| format!("failed to create drag export: {e}"))?;
+        let mut writer = std::io::BufWriter::new(file);
+        let result = if target == "png" {
+            img.write_to(&mut writer, image::ImageOutputFormat::Png)
+        } else {
+            image::DynamicImage::ImageRgb8(img.to_rgb8())
+                .write_to(&mut writer, image::ImageOutputFormat::Jpeg(92))
+        };
+        drop(writer);
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&partial);
+            return Err(format!("failed to encode drag export: {e}"));
+        }
+        if let Err(e) = std::fs::rename(&partial, &dest) {
+            let _ = std::fs::remove_file(&partial);
+            // A concurrent export of the same file may have won the race.
+            if !dest.exists() {
+                return Err(format!("failed to finalize drag export: {e}"));
+            }
+        }
+    }
+
+    Ok(DragExport {
+        path: dest.display().to_string(),
+        format: target.into(),
+        converted: true,
+    })
+}
+
+fn drag_export_dir() -> Result<PathBuf, String> {
+    let dir = std::env::temp_dir().join("yupic-drag");
+    std::fs::create_dir_all(&dir).map_err(|e| format!("failed to create drag temp dir: {e}"))?;
+    Ok(dir)
+}
+
+/// Cache file for `source` in `dir`, keyed by source path, mtime and target format.
+fn drag_export_path(dir: &Path, source: &Path, target: &str) -> PathBuf {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    if let Ok(modified) = std::fs::metadata(source).and_then(|m| m.modified()) {
+        modified.hash(&mut hasher);
+    }
+
+    // Keep the original stem so the dropped file still has a recognizable name.
+    let stem = source
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("image");
+    dir.join(format!("{stem}-{:016x}.{target}", hasher.finish()))
+}
+
+/// Removes expired drag exports and trims the cache to `MAX_DRAG_EXPORTS`,
+/// never touching `keep`. Failures are ignored; this is housekeeping only.
+fn evict_drag_exports(keep: &Path) {
+    let Some(dir) = keep.parent() else {
+        return;
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+
+    let now = SystemTime::now();
+    let mut files: Vec<(SystemTime, PathBuf)> = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path == keep {
+            continue;
+        }
+        let modified = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let expired = now
+            .duration_since(modified)
+            .is_ok_and(|age| age > DRAG_EXPORT_MAX_AGE);
+        if expired {
+            let _ = std::fs::remove_file(&path);
+        } else {
+            files.push((modified, path));
+        }
+    }
+
+    if files.len() >= MAX_DRAG_EXPORTS {
+        files.sort();
+        let excess = files.len() + 1 - MAX_DRAG_EXPORTS;
+        for (_, path) in files.into_iter().take(excess) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// True when the image has an alpha channel with at least one non-opaque pixel.
+/// The HEIF/RAW/JXL loaders always produce RGBA, so the channel alone says nothing.
+fn has_transparent_pixels(img: &image::DynamicImage) -> bool {
+    if !img.color().has_alpha() {
+        return false;
+    }
+    match img.as_rgba8() {
+        Some(rgba) => rgba.pixels().any(|p| p.0[3] < 255),
+        None => img.to_rgba8().pixels().any(|p| p.0[3] < 255),
+    }
+}
+
+/// Decodes the first frame of `path` at full resolution, dispatching on extension
+/// the same way `open_image` does.
+fn load_primary_image(path: &Path) -> Result<image::DynamicImage, String> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match ext.as_str() {
+        "heic" | "heif" => {
+            #[cfg(feature = "heif")]
+            {
+                load_heif(path)
+            }
+            #[cfg(not(feature = "heif"))]
+            {
+                Err("HEIF/HEIC 지원을 빌드 옵션 heif로 활성화하세요".into())
+            }
+        }
+        "jxl" => {
+            #[cfg(feature = "jxl")]
+            {
+                load_jxl(path, JxlColorMode::default())
+            }
+            #[cfg(not(feature = "jxl"))]
+            {
+                Err("JXL 지원을 빌드 옵션 jxl로 활성화하세요".into())
+            }
+        }
+        "dng" | "cr2" | "crw" | "nef" | "nrw" | "orf" | "rw2" | "pef" | "sr2" | "arw" | "raw" | "raf" => {
+            #[cfg(feature = "raw")]
+            {
+                load_raw(path)
+            }
+            #[cfg(not(feature = "raw"))]
+            {
+                Err("RAW 기능이 활성화되지 않았습니다. 서버를 재시작해주세요.".into())
+            }
+        }
+        _ => load_static_image(path).map(|(img, _)| img),
+    }
+}
+
+fn resize_if_needed(img: image::DynamicImage, max_size: Option<u32>) -> image::DynamicImage {
+    if let Some(max) = max_size {
+        if max > 0 && (img.width() > max || img.height() > max) {
+            // Use Nearest for very large images (>8MP), Triangle otherwise
+            let pixels = img.width() as u64 * img.height() as u64;
+            let filter = if pixels > 8_000_000 {
+                image::imageops::FilterType::Nearest
+            } else {
+                image::imageops::FilterType::Triangle
+            };
+            return img.resize(max, max, filter);
+        }
+    }
+    img
+}
+
+fn encode_frame(img: image::DynamicImage, opts: DecodeOptions, delay_ms: u32) -> ImageFrame {
+    let resized = resize_if_needed(img, opts.max_size);
+    let rgba = resized.to_rgba8();
+    let width = rgba.width();
+    let height = rgba.height();
+    let matting = if opts.edge_matting {
+        Some(compute_edge_matting(&rgba))
+    } else {
+        None
+    };
+    let data = rgba.into_raw();
+    let decoded_bytes = data.len() as u64;
+
+    ImageFrame {
+        width,
+        height,
+        delay_ms,
+        data,
+        decoded_bytes,
+        matting,
+    }
+}
+
+/// Width in pixels of the border strips sampled for edge colors.
+const EDGE_STRIP_PX: u32 = 4;
+
+/// Averages each border strip (alpha-weighted, so transparent pixels don't drag
+/// the color toward black) and flags any non-opaque pixel on the outermost edge.
+/// Side strips run the full height, so corners belong to two strips, but
+/// `average` counts every border pixel once.
+fn compute_edge_matting(rgba: &image::RgbaImage) -> EdgeMatting {
+    let (w, h) = rgba.dimensions();
+    let strip_w = EDGE_STRIP_PX.min(w);
+    let strip_h = EDGE_STRIP_PX.min(h);
+
+    let strip = |x0: u32, y0: u32, x1: u32, y1: u32| {
+        let mut sum = EdgeSum::default();
+        for y in y0..y1 {
+            for x in x0..x1 {
+                sum.add(rgba.get_pixel(x, y).0);
+            }
+        }
+        sum.average()
+    };
+
+    let top = strip(0, 0, w, strip_h);
+    let bottom = strip(0, h - strip_h, w, h);
+    let left = strip(0, 0, strip_w, h);
+    let right = strip(w - strip_w, 0, w, h);
+
+    // The border ring: full rows in the top/bottom bands, only the side columns
+    // in between. Ranges are clamped so strips wider than the image don't overlap.
+    let mut total = EdgeSum::default();
+    for y in 0..h {
+        if y < strip_h || y >= h - strip_h {
+            (0..w).for_each(|x| total.add(rgba.get_pixel(x, y).0));
+        } else {
+            (0..strip_w)
+                .chain(strip_w.max(w - strip_w)..w)
+                .for_each(|x| total.add(rgba.get_pixel(x, y).0));
+        }
+    }
+
+    let opaque = |x: u32, y: u32| rgba.get_pixel(x, y).0[3] == 255;
+    let transparent_edges = w > 0
+        && h > 0
+        && ((0..w).any(|x| !opaque(x, 0) || !opaque(x, h - 1))
+            || (0..h).any(|y| !opaque(0, y) || !opaque(w - 1, y)));
+
+    EdgeMatting {
+        top,
+        right,
+        bottom,
+        left,
+        average: total.average(),
+        transparent_edges,
+    }
+}
+
+#[derive(Default)]
+struct EdgeSum {
+    rgb: [u64; 3],
+    alpha: u64,
+}
+
+impl EdgeSum {
+    fn add(&mut self, px: [u8; 4]) {
+        let a = px[3] as u64;
+        for (acc, c) in self.rgb.iter_mut().zip(px) {
+            *acc += c as u64 * a;
+        }
+        self.alpha += a;
+    }
+
+    fn average(&self) -> [u8; 3] {
+        if self.alpha == 0 {
+            return [0, 0, 0];
+        }
+        self.rgb.map(|c| ((c + self.alpha / 2) / self.alpha) as u8)
+    }
+}
+
+/// The app's shared ICC -> sRGB step: converts float RGBA (0..1, unclamped)
+/// tagged with `icc` to sRGB with lcms, leaving alpha untouched. Decoders that
+/// opt into lcms color management call this before quantizing; 8-bit sources
+/// widen to float first so every format goes through the same transform.
+#[cfg(feature = "lcms")]
+#[cfg_attr(not(feature = "jxl"), allow(dead_code))]
+fn convert_icc_to_srgb(pixels: &mut [[f32; 4]], icc: &[u8]) -> Result<(), String> {
+    use lcms2::{Flags, Intent, PixelFormat, Profile, Transform};
+
+    let source = Profile::new_icc(icc).map_err(|e| format!("failed to parse icc profile: {e}"))?;
+    let srgb = Profile::new_srgb();
+    let transform: Transform<[f32; 4], [f32; 4]> = Transform::new_flags(
+        &source,
+        PixelFormat::RGBA_FLT,
+        &srgb,
+        PixelFormat::RGBA_FLT,
+        Intent::Perceptual,
+        Flags::COPY_ALPHA,
+    )
+    .map_err(|e| format!("failed to create color transform: {e}"))?;
+
+    transform.transform_in_place(pixels);
+    Ok(())
+}
+
+fn load_static_image(path: &Path) -> Result<(image::DynamicImage, String), String> {
+    let mut reader = image::io::Reader::open(path)
+        .map_err(|err| format!("failed to open file {}: {err}", path.display()))?;
+    
+    // image 0.24 uses set_limits or similar? Actually Reader has no_limits() in some versions.
+    // Let's use the standard 0.24 way if no_limits() is missing.
+    reader.no_limits();
+    
+    let reader = reader.with_guessed_format()
+        .map_err(|err| format!("failed to guess format for {}: {err}", path.display()))?;
+
+    let format = reader
+        .format()
+        .map(|fmt| format!("{fmt:?}"))
+        .unwrap_or_else(|| "unknown".into());
+
+    let decoded = reader
+        .decode()
+        .map_err(|err| format!("failed to decode image {}: {err}", path.display()))?;
+
+    Ok((decoded, format))
+}
+
+fn decode_static_image(path: &Path, opts: DecodeOptions) -> Result<(ImageFrame, String), String> {
+    let (decoded, format) = load_static_image(path)?;
+    Ok((encode_frame(decoded, opts, 0), format))
+}
+
+fn decode_gif(path: &Path, opts: DecodeOptions) -> Result<(Vec<ImageFrame>, String), String> {
+    use image::codecs::gif::GifDecoder;
+    use image::AnimationDecoder;
+    use std::fs::File;
+
+    let file = File::open(path).map_err(|err| format!("failed to open gif: {err}"))?;
+    let reader = BufReader::new(file);
+    let decoder = GifDecoder::new(reader).map_err(|err| format!("failed to read gif: {err}"))?;
+
+    // Frames before the window still have to be decoded for compositing, but are
+    // dropped immediately instead of being collected.
+    let (start, end) = opts.frame_range.unwrap_or((0, usize::MAX));
+    let count = (end - start).min(MAX_ANIM_FRAMES);
+
+    let mut out = Vec::with_capacity(count.min(64));
+    for frame in decoder.into_frames().skip(start).take(count) {
+        let frame = frame.map_err(|err| format!("failed to collect gif frames: {err}"))?;
+        let delay: image::Delay = frame.delay();
+        let (num, denom) = delay.numer_denom_ms();
+        let delay_ms = if denom == 0 {
+            num
+        } else {
+            let ms = (num as f32 / denom as f32).round() as u32;
+            ms.max(10)
+        };
+
+        let buffer = frame.into_buffer();
+        let dynamic = image::DynamicImage::ImageRgba8(buffer);
+        out.push(encode_frame(dynamic, opts, delay_ms));
+    }
+
+    Ok((out, "gif".into()))
+}
+
+/// Counts image descriptors by walking the GIF block structure without
+/// decompressing any frame data.
+fn gif_frame_count<R: Read>(mut reader: R) -> Result<usize, String> {
+    let bad = |what: &str| format!("malformed gif: {what}");
+    let mut byte = [0u8; 1];
+    let mut next = |reader: &mut R| -> Result<u8, String> {
+        reader.read_exact(&mut byte).map_err(|_| bad("unexpected end of file"))?;
+        Ok(byte[0])
+    };
+    let skip = |reader: &mut R, len: u64| -> Result<(), String> {
+        let skipped = std::io::copy(&mut reader.take(len), &mut std::io::sink())
+            .map_err(|e| format!("failed to read gif: {e}"))?;
+        if skipped == len {
+            Ok(())
+        } else {
+            Err(bad("unexpected end of file"))
+        }
+    };
+    // Color tables are 3 * 2^(n+1) bytes when bit 7 of the packed field is set.
+    let color_table = |packed: u8| if packed & 0x80 != 0 { 3u64 << ((packed & 7) + 1) } else { 0 };
+
+    let mut header = [0u8; 13];
+    reader.read_exact(&mut header).map_err(|_| bad("truncated header"))?;
+    if &header[..3] != b"GIF" {
+        return Err(bad("missing signature"));
+    }
+    skip(&mut reader, color_table(header[10]))?;
+
+    let mut frames = 0;
+    loop {
+        // Plenty of GIFs in the wild stop without a trailer; count what's there.
+        let Ok(introducer) = next(&mut reader) else {
+            return Ok(frames);
+        };
+        match introducer {
+            0x2C => {
+                let mut descriptor = [0u8; 9];
+                reader.read_exact(&mut descriptor).map_err(|_| bad("truncated image descriptor"))?;
+                skip(&mut reader, color_table(descriptor[8]) + 1)?; // + LZW minimum code size
+                frames += 1;
+            }
+            0x21 => {
+                next(&mut reader)?; // extension label
+            }
+            0x3B => return Ok(frames),
+            other => return Err(bad(&format!("unexpected block 0x{other:02x}"))),
+        }
+        // Both image data and extensions end in a run of length-prefixed sub-blocks.
+        loop {
+            let len = next(&mut reader)?;
+            if len == 0 {
+                break;
+            }
+            skip(&mut reader, len as u64)?;
+        }
+    }
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif(path: &Path, opts: DecodeOptions) -> Result<(Vec<ImageFrame>, String), String> {
+    let dynamic = load_heif(path)?;
+    Ok((vec![encode_frame(dynamic, opts, 0)], "heif".into()))
+}
+
+#[cfg(feature = "heif")]
+fn load_heif(_path: &Path) -> Result<image::DynamicImage, String> {
+    use libheif_rs::LibHeif;
+    
+    let path_str = _path
+        .to_str()
+        .ok_or_else(|| "invalid heif path".to_string())?;
+
+    let lib_heif = LibHeif::new();
+    let ctx = HeifContext::read_from_file(path_str)
         .map_err(|e| format!("failed to read heif {}: {e}", path_str))?;
     
     let handle = ctx
         .primary_image_handle()
         .map_err(|e| format!("failed to get primary image: {e}"))?;
 
-    let image = lib_heif
-        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
-        .map_err(|e| format!("failed to decode heif: {e}"))?;
+    let image = match lib_heif.decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None) {
+        Ok(image) => image,
+        Err(e) => {
+            // libheif normally stitches grids itself, so only fall back to manual
+            // tiling when it gives up; the grid's irot/imir are reapplied after.
+            let info = std::fs::read(_path)
+                .ok()
+                .and_then(|bytes| heif_container_info(&bytes))
+                .unwrap_or_default();
+            if info.primary_item_type == "grid" {
+                return decode_heif_grid(&lib_heif, &ctx, &info).map_err(|grid_err| {
+                    format!(
+                        "heif_unsupported_variant ({}): {e}; grid stitching failed: {grid_err}",
+                        info.describe()
+                    )
+                });
+            }
+            if info.is_unsupported_variant() {
+                return Err(format!("heif_unsupported_variant ({}): {e}", info.describe()));
+            }
+            return Err(format!("failed to decode heif: {e}"));
+        }
+    };
+
+    let width = image.width();
+    let height = image.height();
+    let planes = image.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| "heif interleaved plane missing".to_string())?;
+
+    let rgba_data = heif_rgb_to_rgba(plane.data, plane.stride, width, height);
+
+    let dynamic = image::DynamicImage::ImageRgba8(
+        image::RgbaImage::from_raw(width, height, rgba_data)
+            .ok_or_else(|| "failed to create rgba image from heif data".to_string())?
+    );
+
+    Ok(dynamic)
+}
+
+/// Copies an interleaved 8-bit RGB plane into tightly packed RGBA, honoring row padding.
+#[cfg(feature = "heif")]
+fn heif_rgb_to_rgba(data: &[u8], stride: usize, width: u32, height: u32) -> Vec<u8> {
+    let row_bytes = width as usize * 3;
+    let mut rgba_data = Vec::with_capacity(width as usize * height as usize * 4);
+    for row in data.chunks(stride.max(row_bytes)).take(height as usize) {
+        for chunk in row[..row_bytes.min(row.len())].chunks_exact(3) {
+            rgba_data.extend_from_slice(&[chunk[0], chunk[1], chunk[2], 255]);
+        }
+    }
+    rgba_data
+}
+
+/// Decodes each `dimg` tile of a grid item with libheif and stitches them into
+/// the grid's declared layout, then applies the primary item's irot/imir.
+#[cfg(feature = "heif")]
+fn decode_heif_grid(
+    lib_heif: &libheif_rs::LibHeif,
+    ctx: &HeifContext,
+    info: &HeifContainerInfo,
+) -> Result<image::DynamicImage, String> {
+    let layout = info
+        .grid_layout
+        .ok_or_else(|| "heif grid descriptor missing".to_string())?;
+    let first = *info.grid_tiles.first().ok_or("heif grid has no tiles")?;
+    // The handle reports the coded size without decoding anything.
+    let first_handle = ctx
+        .image_handle(first)
+        .map_err(|e| format!("failed to get heif tile {first}: {e}"))?;
+    let tile_size = (first_handle.width(), first_handle.height());
+
+    let canvas = stitch_grid_tiles(layout, &info.grid_tiles, tile_size, |tile_id| {
+        let tile_handle = ctx
+            .image_handle(tile_id)
+            .map_err(|e| format!("failed to get heif tile {tile_id}: {e}"))?;
+        let tile = lib_heif
+            .decode(&tile_handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+            .map_err(|e| format!("failed to decode heif tile {tile_id}: {e}"))?;
+        let (tile_w, tile_h) = (tile.width(), tile.height());
+        let planes = tile.planes();
+        let plane = planes
+            .interleaved
+            .ok_or_else(|| "heif tile interleaved plane missing".to_string())?;
+        image::RgbaImage::from_raw(
+            tile_w,
+            tile_h,
+            heif_rgb_to_rgba(plane.data, plane.stride, tile_w, tile_h),
+        )
+        .ok_or_else(|| "failed to create rgba image from heif tile".to_string())
+    })?;
+
+    Ok(apply_heif_transforms(
+        image::DynamicImage::ImageRgba8(canvas),
+        &info.transforms,
+    ))
+}
+
+/// Validates a grid against its tile size, then places `decode_tile(id)` for
+/// each tile row-major, cropping the right/bottom tiles where they overhang.
+/// Nothing is decoded or allocated until the layout has been checked.
+#[cfg(feature = "heif")]
+fn stitch_grid_tiles<F>(
+    layout: HeifGridLayout,
+    tiles: &[u32],
+    (tile_w, tile_h): (u32, u32),
+    mut decode_tile: F,
+) -> Result<image::RgbaImage, String>
+where
+    F: FnMut(u32) -> Result<image::RgbaImage, String>,
+{
+    let expected = layout.rows as usize * layout.columns as usize;
+    if expected > MAX_HEIF_GRID_TILES {
+        return Err(format!("heif grid has {expected} tiles, more than the {MAX_HEIF_GRID_TILES} supported"));
+    }
+    if tiles.len() != expected {
+        return Err(format!(
+            "heif grid is {}x{} but references {} tiles",
+            layout.columns,
+            layout.rows,
+            tiles.len()
+        ));
+    }
+    if tile_w == 0 || tile_h == 0 {
+        return Err("heif grid tiles are empty".into());
+    }
+    // The output may crop the last column/row, but never by a whole tile.
+    let spans = |count: u32, tile: u32, out: u32| {
+        let (count, tile, out) = (count as u64, tile as u64, out as u64);
+        (count - 1) * tile < out && out <= count * tile
+    };
+    if !spans(layout.columns, tile_w, layout.width) || !spans(layout.rows, tile_h, layout.height) {
+        return Err(format!(
+            "heif grid of {}x{} {tile_w}x{tile_h} tiles can't produce {}x{}",
+            layout.columns, layout.rows, layout.width, layout.height
+        ));
+    }
+    let pixels = layout.width as u64 * layout.height as u64;
+    if pixels > MAX_HEIF_GRID_PIXELS {
+        return Err(format!(
+            "heif grid output {}x{} exceeds the {MAX_HEIF_GRID_PIXELS}-pixel limit",
+            layout.width, layout.height
+        ));
+    }
+
+    let mut canvas = image::RgbaImage::new(layout.width, layout.height);
+    for (index, &tile_id) in tiles.iter().enumerate() {
+        let tile = decode_tile(tile_id)?;
+        if tile.dimensions() != (tile_w, tile_h) {
+            let (w, h) = tile.dimensions();
+            return Err(format!("heif tile {tile_id} is {w}x{h}, expected {tile_w}x{tile_h}"));
+        }
+        let x = (index as u32 % layout.columns) as i64 * tile_w as i64;
+        let y = (index as u32 / layout.columns) as i64 * tile_h as i64;
+        // replace() clips to the canvas, which handles tiles that overhang the output size.
+        image::imageops::replace(&mut canvas, &tile, x, y);
+    }
+    Ok(canvas)
+}
+
+/// Applies `irot`/`imir` properties in the order the item lists them.
+#[cfg(feature = "heif")]
+fn apply_heif_transforms(
+    mut img: image::DynamicImage,
+    transforms: &[HeifTransform],
+) -> image::DynamicImage {
+    for transform in transforms {
+        img = match *transform {
+            // irot angles are anticlockwise; image's rotations are clockwise.
+            HeifTransform::Rotate(1) => img.rotate270(),
+            HeifTransform::Rotate(2) => img.rotate180(),
+            HeifTransform::Rotate(3) => img.rotate90(),
+            HeifTransform::Rotate(_) => img,
+            HeifTransform::Mirror { vertical_axis: true } => img.fliph(),
+            HeifTransform::Mirror { vertical_axis: false } => img.flipv(),
+        };
+    }
+    img
+}
+
+/// What the HEIF container declares about itself, used to stitch grids libheif
+/// rejects and to name the variant in unsupported-file errors.
+#[cfg(feature = "heif")]
+#[derive(Default)]
+struct HeifContainerInfo {
+    major_brand: String,
+    compatible_brands: Vec<String>,
+    primary_item_type: String,
+    grid_tiles: Vec<u32>,
+    grid_layout: Option<HeifGridLayout>,
+    hevc_profile: Option<u8>,
+    /// The primary item's `irot`/`imir`, in association order.
+    transforms: Vec<HeifTransform>,
+}
+
+#[cfg(feature = "heif")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HeifTransform {
+    /// Anticlockwise quarter turns.
+    Rotate(u8),
+    Mirror { vertical_axis: bool },
+}
+
+/// The `ImageGrid` descriptor stored as the grid item's payload.
+#[cfg(feature = "heif")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct HeifGridLayout {
+    rows: u32,
+    columns: u32,
+    width: u32,
+    height: u32,
+}
+
+#[cfg(feature = "heif")]
+impl HeifContainerInfo {
+    /// True when the container itself names something libheif can't decode, as
+    /// opposed to a damaged file or an I/O failure.
+    fn is_unsupported_variant(&self) -> bool {
+        const DOLBY_VISION: [&str; 4] = ["dvhe", "dvh1", "dvav", "dva1"];
+        const DECODABLE_ITEMS: [&str; 6] = ["hvc1", "av01", "grid", "iden", "iovl", "jpeg"];
+        let dolby_vision = std::iter::once(&self.major_brand)
+            .chain(&self.compatible_brands)
+            .any(|brand| DOLBY_VISION.contains(&brand.as_str()));
+        let unknown_item = !self.primary_item_type.is_empty()
+            && !DECODABLE_ITEMS.contains(&self.primary_item_type.as_str());
+        let unknown_profile = self.hevc_profile.is_some_and(|p| !(1..=4).contains(&p));
+        dolby_vision || unknown_item || unknown_profile
+    }
+
+    fn describe(&self) -> String {
+        let profile = match self.hevc_profile {
+            Some(1) => "Main".to_string(),
+            Some(2) => "Main 10".to_string(),
+            Some(3) => "Main Still Picture".to_string(),
+            Some(4) => "Range Extensions".to_string(),
+            Some(other) => format!("profile_idc {other}"),
+            None => "unknown".to_string(),
+        };
+        format!(
+            "brand {}, compatible [{}], item {}, hevc profile {}",
+            if self.major_brand.is_empty() { "unknown" } else { &self.major_brand },
+            self.compatible_brands.join(","),
+            if self.primary_item_type.is_empty() { "unknown" } else { &self.primary_item_type },
+            profile,
+        )
+    }
+}
+
+/// Parses `ftyp` and the `meta` box (`pitm`, `iinf`, `iref`, `iloc`/`idat`, `iprp`) of a HEIF file.
+#[cfg(feature = "heif")]
+fn heif_container_info(bytes: &[u8]) -> Option<HeifContainerInfo> {
+    let fourcc = |b: &[u8]| String::from_utf8_lossy(b).trim_end_matches('\0').to_string();
+    let be16 = |b: &[u8], at: usize| -> Option<u32> {
+        Some(u16::from_be_bytes([*b.get(at)?, *b.get(at + 1)?]) as u32)
+    };
+    let be32 = |b: &[u8], at: usize| -> Option<u32> {
+        Some(u32::from_be_bytes([*b.get(at)?, *b.get(at + 1)?, *b.get(at + 2)?, *b.get(at + 3)?]))
+    };
+    // Item ids are 16-bit in version 0 of pitm/iinf/iref/ipma, 32-bit otherwise.
+    let item_id = |b: &[u8], at: usize, wide: bool| -> Option<(u32, usize)> {
+        if wide {
+            Some((be32(b, at)?, at + 4))
+        } else {
+            Some((be16(b, at)?, at + 2))
+        }
+    };
 
-    let width = image.width();
-    let height = image.height();
-    let planes = image.planes();
-    let plane = planes
-        .interleaved
-        .ok_or_else(|| "heif interleaved plane missing".to_string())?;
+    let mut info = HeifContainerInfo::default();
+    let boxes = mp4_boxes(bytes);
 
-    let rgb_data = plane.data;
-    let mut rgba_data = Vec::with_capacity(width as usize * height as usize * 4);
-    for chunk in rgb_data.chunks(3) {
-        rgba_data.push(chunk[0]);
-        rgba_data.push(chunk[1]);
-        rgba_data.push(chunk[2]);
-        rgba_data.push(255);
+    if let Some((_, ftyp)) = boxes.iter().find(|(k, _)| k == b"ftyp") {
+        info.major_brand = fourcc(ftyp.get(0..4)?);
+        info.compatible_brands = ftyp.get(8..)?.chunks_exact(4).map(fourcc).collect();
     }
 
-    let dynamic = image::DynamicImage::ImageRgba8(
-        image::RgbaImage::from_raw(width, height, rgba_data)
-            .ok_or_else(|| "failed to create rgba image from heif data".to_string())?
-    );
-    let resized = resize_if_needed(dynamic, max_size);
-    let final_rgba = resized.to_rgba8();
-    let (f_w, f_h) = (final_rgba.width(), final_rgba.height());
-    let data = base64::engine::general_purpose::STANDARD.encode(final_rgba.into_raw());
-
-    Ok((
-        vec![ImageFrame {
-            width: f_w,
-            height: f_h,
-            delay_ms: 0,
-            data,
-        }],
-        "heif".into(),
-    ))
+    let meta = boxes.iter().find(|(k, _)| k == b"meta")?.1.get(4..)?;
+    let children = mp4_boxes(meta);
+    let child = |kind: &[u8; 4]| children.iter().find(|(k, _)| k == kind).map(|(_, p)| *p);
+
+    let pitm = child(b"pitm")?;
+    let (primary, _) = item_id(pitm, 4, *pitm.first()? != 0)?;
+
+    if let Some(iinf) = child(b"iinf") {
+        let entries_at = if *iinf.first()? == 0 { 6 } else { 8 };
+        for (kind, infe) in mp4_boxes(iinf.get(entries_at..)?) {
+            // Only infe version >= 2 carries an item_type.
+            if &kind != b"infe" || infe.first().copied().unwrap_or(0) < 2 {
+                continue;
+            }
+            let (id, at) = item_id(infe, 4, infe[0] >= 3)?;
+            if id == primary {
+                info.primary_item_type = fourcc(infe.get(at + 2..at + 6)?);
+            }
+        }
+    }
+
+    if let Some(iref) = child(b"iref") {
+        let wide = *iref.first()? != 0;
+        for (kind, refs) in mp4_boxes(iref.get(4..)?) {
+            let (from, at) = item_id(refs, 0, wide)?;
+            if &kind != b"dimg" || from != primary {
+                continue;
+            }
+            let count = be16(refs, at)? as usize;
+            let mut at = at + 2;
+            for _ in 0..count {
+                let (to, next) = item_id(refs, at, wide)?;
+                info.grid_tiles.push(to);
+                at = next;
+            }
+        }
+    }
+
+    // The grid's rows/columns live in its item payload, usually inside idat.
+    if info.primary_item_type == "grid" {
+        info.grid_layout = child(b"iloc")
+            .and_then(|iloc| heif_item_location(iloc, primary))
+            .and_then(|(method, offset, length)| {
+                let source = match method {
+                    0 => bytes,
+                    1 => child(b"idat")?,
+                    _ => return None,
+                };
+                source.get(offset..offset.checked_add(length)?)
+            })
+            .and_then(heif_grid_layout);
+    }
+
+    // Grids have no hvcC of their own; take the profile from the first tile instead.
+    let coded_item = info.grid_tiles.first().copied().unwrap_or(primary);
+    if let Some(iprp) = child(b"iprp") {
+        let iprp_boxes = mp4_boxes(iprp);
+        let ipco = iprp_boxes.iter().find(|(k, _)| k == b"ipco").map(|(_, p)| mp4_boxes(p));
+        let ipma = iprp_boxes.iter().find(|(k, _)| k == b"ipma").map(|(_, p)| *p);
+        if let (Some(ipco), Some(ipma)) = (ipco, ipma) {
+            let wide = *ipma.first()? >= 1;
+            let large_index = ipma.get(3).is_some_and(|flags| flags & 1 != 0);
+            let entries = be32(ipma, 4)?;
+            let mut at = 8;
+            for _ in 0..entries {
+                let (id, next) = item_id(ipma, at, wide)?;
+                let assoc_count = *ipma.get(next)? as usize;
+                at = next + 1;
+                for _ in 0..assoc_count {
+                    let index = if large_index {
+                        let v = be16(ipma, at)? & 0x7fff;
+                        at += 2;
+                        v
+                    } else {
+                        let v = (*ipma.get(at)? & 0x7f) as u32;
+                        at += 1;
+                        v
+                    } as usize;
+                    let Some((kind, prop)) = index.checked_sub(1).and_then(|i| ipco.get(i)) else {
+                        continue;
+                    };
+                    if id == coded_item && kind == b"hvcC" {
+                        info.hevc_profile = prop.get(1).map(|b| b & 0x1f);
+                    }
+                    if id == primary {
+                        match kind {
+                            b"irot" => info.transforms.push(HeifTransform::Rotate(prop.first()? & 3)),
+                            b"imir" => info.transforms.push(HeifTransform::Mirror {
+                                vertical_axis: prop.first()? & 1 == 0,
+                            }),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Some(info)
+}
+
+/// Looks up an item in `iloc` and returns its construction method plus the
+/// absolute offset and length of its data. Only single-extent items are handled,
+/// which is all the small descriptor items we read ever use.
+#[cfg(feature = "heif")]
+fn heif_item_location(iloc: &[u8], wanted: u32) -> Option<(u8, usize, usize)> {
+    let sized = |at: usize, size: u8| -> Option<u64> {
+        match size {
+            0 => Some(0),
+            4 => Some(u32::from_be_bytes(iloc.get(at..at + 4)?.try_into().ok()?) as u64),
+            8 => Some(u64::from_be_bytes(iloc.get(at..at + 8)?.try_into().ok()?)),
+            _ => None,
+        }
+    };
+    let version = *iloc.first()?;
+    let (offset_size, length_size) = (iloc.get(4)? >> 4, iloc.get(4)? & 0xf);
+    let base_offset_size = iloc.get(5)? >> 4;
+    let index_size = if version >= 1 { iloc.get(5)? & 0xf } else { 0 };
+    let id_size = if version < 2 { 2 } else { 4 };
+    let count = if version < 2 {
+        u16::from_be_bytes([*iloc.get(6)?, *iloc.get(7)?]) as u64
+    } else {
+        sized(6, 4)?
+    };
+    let mut at = 6 + id_size;
+
+    for _ in 0..count {
+        let id = if id_size == 2 {
+            u16::from_be_bytes([*iloc.get(at)?, *iloc.get(at + 1)?]) as u64
+        } else {
+            sized(at, 4)?
+        };
+        at += id_size;
+        let method = if version >= 1 {
+            let method = iloc.get(at + 1)? & 0xf;
+            at += 2;
+            method
+        } else {
+            0
+        };
+        at += 2; // data_reference_index
+        let base = sized(at, base_offset_size)?;
+        at += base_offset_size as usize;
+        let extent_count = u16::from_be_bytes([*iloc.get(at)?, *iloc.get(at + 1)?]);
+        at += 2;
+
+        let mut first_extent = None;
+        for _ in 0..extent_count {
+            at += index_size as usize;
+            let offset = sized(at, offset_size)?;
+            at += offset_size as usize;
+            let length = sized(at, length_size)?;
+            at += length_size as usize;
+            first_extent.get_or_insert((offset, length));
+        }
+
+        if id == wanted as u64 {
+            if extent_count != 1 {
+                return None;
+            }
+            let (offset, length) = first_extent?;
+            let offset = usize::try_from(base.checked_add(offset)?).ok()?;
+            return Some((method, offset, usize::try_from(length).ok()?));
+        }
+    }
+    None
+}
+
+/// Parses an `ImageGrid` item payload: rows/columns minus one, then the output
+/// size in 16 or 32 bits depending on flags bit 0.
+#[cfg(feature = "heif")]
+fn heif_grid_layout(data: &[u8]) -> Option<HeifGridLayout> {
+    if *data.first()? != 0 {
+        return None;
+    }
+    let wide = data.get(1)? & 1 != 0;
+    let rows = *data.get(2)? as u32 + 1;
+    let columns = *data.get(3)? as u32 + 1;
+    let (width, height) = if wide {
+        (
+            u32::from_be_bytes(data.get(4..8)?.try_into().ok()?),
+            u32::from_be_bytes(data.get(8..12)?.try_into().ok()?),
+        )
+    } else {
+        (
+            u16::from_be_bytes([*data.get(4)?, *data.get(5)?]) as u32,
+            u16::from_be_bytes([*data.get(6)?, *data.get(7)?]) as u32,
+        )
+    };
+    Some(HeifGridLayout { rows, columns, width, height })
+}
+
+#[cfg(feature = "jxl")]
+fn decode_jxl(path: &Path, opts: DecodeOptions) -> Result<(Vec<ImageFrame>, String), String> {
+    let dynamic = load_jxl(path, opts.jxl_color)?;
+    Ok((vec![encode_frame(dynamic, opts, 0)], "jxl".into()))
 }
 
 #[cfg(feature = "jxl")]
-fn decode_jxl(_path: &Path, max_size: Option<u32>) -> Result<(Vec<ImageFrame>, String), String> {
+fn load_jxl(_path: &Path, color: JxlColorMode) -> Result<image::DynamicImage, String> {
     let path_str = _path.display();
     let image = JxlImage::builder()
         .open(_path)
@@ -362,41 +2236,45 @@ fn decode_jxl(_path: &Path, max_size: Option<u32>) -> Result<(Vec<ImageFrame>, S
         return Err(format!("jxl buffer write mismatch: expected {samples}, got {written}"));
     }
 
-    let mut rgba_data = Vec::with_capacity(width as usize * height as usize * 4);
-    for chunk in buf.chunks(channels as usize) {
-        let r = chunk[0].clamp(0.0, 1.0);
-        let g = chunk[1].clamp(0.0, 1.0);
-        let b = chunk[2].clamp(0.0, 1.0);
-        let a = if channels >= 4 { chunk[3].clamp(0.0, 1.0) } else { 1.0 };
+    let mut pixels: Vec<[f32; 4]> = buf
+        .chunks(channels as usize)
+        .map(|c| [c[0], c[1], c[2], if channels >= 4 { c[3] } else { 1.0 }])
+        .collect();
 
-        rgba_data.push((r * 255.0 + 0.5) as u8);
-        rgba_data.push((g * 255.0 + 0.5) as u8);
-        rgba_data.push((b * 255.0 + 0.5) as u8);
-        rgba_data.push((a * 255.0 + 0.5) as u8);
+    // Convert while still in float so the transform sees the decoder's full
+    // precision and out-of-range values; quantize only afterwards.
+    if color == JxlColorMode::Lcms {
+        #[cfg(feature = "lcms")]
+        {
+            convert_icc_to_srgb(&mut pixels, &image.rendered_icc())?;
+        }
+        #[cfg(not(feature = "lcms"))]
+        {
+            return Err("lcms 색상 관리를 빌드 옵션 lcms로 활성화하세요".into());
+        }
     }
 
-    let dynamic = image::DynamicImage::ImageRgba8(
-        image::RgbaImage::from_raw(width, height, rgba_data)
-            .ok_or_else(|| "failed to create rgba image from jxl data".to_string())?
-    );
-    let resized = resize_if_needed(dynamic, max_size);
-    let final_rgba = resized.to_rgba8();
-    let (f_w, f_h) = (final_rgba.width(), final_rgba.height());
-    let data = base64::engine::general_purpose::STANDARD.encode(final_rgba.into_raw());
-
-    Ok((
-        vec![ImageFrame {
-            width: f_w,
-            height: f_h,
-            delay_ms: 0,
-            data,
-        }],
-        "jxl".into(),
-    ))
+    let mut rgba_data = Vec::with_capacity(width as usize * height as usize * 4);
+    for px in &pixels {
+        for &v in px {
+            rgba_data.push((v.clamp(0.0, 1.0) * 255.0 + 0.5) as u8);
+        }
+    }
+
+    let rgba = image::RgbaImage::from_raw(width, height, rgba_data)
+        .ok_or_else(|| "failed to create rgba image from jxl data".to_string())?;
+
+    Ok(image::DynamicImage::ImageRgba8(rgba))
+}
+
+#[cfg(feature = "raw")]
+fn decode_raw(path: &Path, opts: DecodeOptions) -> Result<(Vec<ImageFrame>, String), String> {
+    let dynamic = load_raw(path)?;
+    Ok((vec![encode_frame(dynamic, opts, 0)], "raw".into()))
 }
 
 #[cfg(feature = "raw")]
-fn decode_raw(_path: &Path, max_size: Option<u32>) -> Result<(Vec<ImageFrame>, String), String> {
+fn load_raw(_path: &Path) -> Result<image::DynamicImage, String> {
     let path_str = _path
         .to_str()
         .ok_or_else(|| "invalid raw path".to_string())?
@@ -437,20 +2315,8 @@ fn decode_raw(_path: &Path, max_size: Option<u32>) -> Result<(Vec<ImageFrame>, S
                 image::RgbaImage::from_raw(width, height, rgba_data)
                     .ok_or_else(|| "failed to create rgba image from raw data".to_string())?
             );
-            let resized = resize_if_needed(dynamic, max_size);
-            let final_rgba = resized.to_rgba8();
-            let (f_w, f_h) = (final_rgba.width(), final_rgba.height());
-            let data = base64::engine::general_purpose::STANDARD.encode(final_rgba.into_raw());
-
-            Ok((
-                vec![ImageFrame {
-                    width: f_w,
-                    height: f_h,
-                    delay_ms: 0,
-                    data,
-                }],
-                "raw".into(),
-            ))
+
+            Ok(dynamic)
         }
         1 => {
             if samples_f32.len() < pixels {
@@ -489,20 +2355,8 @@ fn decode_raw(_path: &Path, max_size: Option<u32>) -> Result<(Vec<ImageFrame>, S
                 image::RgbaImage::from_raw(width, height, rgba_data)
                     .ok_or_else(|| "failed to create grayscale image from raw data".to_string())?
             );
-            let resized = resize_if_needed(dynamic, max_size);
-            let final_rgba = resized.to_rgba8();
-            let (f_w, f_h) = (final_rgba.width(), final_rgba.height());
-            let data = base64::engine::general_purpose::STANDARD.encode(final_rgba.into_raw());
-
-            Ok((
-                vec![ImageFrame {
-                    width: f_w,
-                    height: f_h,
-                    delay_ms: 0,
-                    data,
-                }],
-                "raw".into(),
-            ))
+
+            Ok(dynamic)
         }
         other => Err(format!("unsupported RAW cpp={} (only mono or rgb supported)", other)),
     }
@@ -513,7 +2367,760 @@ pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
-    .invoke_handler(tauri::generate_handler![open_image, get_directory_images, get_metadata])
+        .manage(DirectorySnapshots::default())
+        .manage(DecodePool::new(DecodePoolConfig::default()).expect("failed to build decode pool"))
+    .invoke_handler(tauri::generate_handler![
+        open_image,
+        get_directory_images,
+        get_metadata,
+        prepare_drag_export,
+        start_drag_export,
+        extract_motion_video,
+        diff_directory,
+        extract_embedded_preview,
+        configure_decode_pool
+    ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn isobmff_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn fake_mp4(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut mvhd = vec![0u8; 20];
+        mvhd[12..16].copy_from_slice(&timescale.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&duration.to_be_bytes());
+        let mut out = isobmff_box(b"ftyp", b"isom\0\0\x02\0isommp41");
+        out.extend(isobmff_box(b"moov", &isobmff_box(b"mvhd", &mvhd)));
+        out
+    }
+
+    /// Minimal JPEG: optional APP1 payload, one scan whose entropy data is `scan`, EOI.
+    fn fake_jpeg(app1: &[u8], scan: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        out.extend(((app1.len() + 2) as u16).to_be_bytes());
+        out.extend_from_slice(app1);
+        out.extend([0xFF, 0xDA, 0x00, 0x08, 1, 1, 0, 0, 0x3F, 0]);
+        out.extend_from_slice(scan);
+        out.extend([0xFF, 0xD9]);
+        out
+    }
+
+    #[test]
+    fn mp4_boxes_splits_siblings() {
+        let mut data = isobmff_box(b"ftyp", b"isom");
+        data.extend(isobmff_box(b"free", &[]));
+        // size 0 extends to the end of the data
+        data.extend([0, 0, 0, 0]);
+        data.extend(b"mdat\x01\x02");
+
+        let boxes = mp4_boxes(&data);
+        let kinds: Vec<&[u8; 4]> = boxes.iter().map(|(k, _)| k).collect();
+        assert_eq!(kinds, [b"ftyp", b"free", b"mdat"]);
+        assert_eq!(boxes[0].1, b"isom");
+        assert_eq!(boxes[2].1, &[1, 2]);
+    }
+
+    #[test]
+    fn mp4_duration_reads_mvhd_versions() {
+        assert_eq!(mp4_duration_ms(&fake_mp4(600, 1500)), Some(2500));
+
+        let mut mvhd = vec![1u8, 0, 0, 0];
+        mvhd.extend([0u8; 16]);
+        mvhd.extend(1000u32.to_be_bytes());
+        mvhd.extend(3_000u64.to_be_bytes());
+        let v1 = isobmff_box(b"moov", &isobmff_box(b"mvhd", &mvhd));
+        assert_eq!(mp4_duration_ms(&v1), Some(3000));
+
+        assert_eq!(mp4_duration_ms(&fake_mp4(0, 10)), None);
+    }
+
+    #[test]
+    fn motion_video_from_google_xmp_offset() {
+        let video = fake_mp4(1000, 1200);
+        let xmp = format!("GCamera:MicroVideoOffset=\"{}\"", video.len());
+        let mut photo = fake_jpeg(xmp.as_bytes(), &[0x12, 0x34]);
+        let start = photo.len();
+        photo.extend(&video);
+
+        assert_eq!(find_motion_video_offset(&photo), Some(start));
+    }
+
+    #[test]
+    fn motion_video_from_samsung_marker() {
+        let mut photo = fake_jpeg(b"", &[0x12, 0x34]);
+        photo.extend(b"MotionPhoto_Data");
+        let start = photo.len();
+        photo.extend(fake_mp4(1000, 1200));
+
+        assert_eq!(find_motion_video_offset(&photo), Some(start));
+    }
+
+    #[test]
+    fn motion_video_scan_ignores_ftyp_inside_jpeg_data() {
+        // A valid-looking ftyp box inside the entropy-coded data, plus stuffed
+        // 0xFF00 and a restart marker the EOI walk has to step over.
+        let mut scan = vec![0xFF, 0x00, 0xFF, 0xD0];
+        scan.extend(isobmff_box(b"ftyp", b"isom\0\0\0\0"));
+        scan.extend([0xAB; 8]);
+        let mut photo = fake_jpeg(b"", &scan);
+        let start = photo.len();
+        photo.extend(fake_mp4(1000, 1200));
+
+        assert_eq!(jpeg_end(&photo), Some(start));
+        assert_eq!(find_motion_video_offset(&photo), Some(start));
+
+        let still = fake_jpeg(b"", &scan);
+        assert_eq!(find_motion_video_offset(&still), None);
+    }
+
+    #[test]
+    fn looks_like_mp4_checks_size_and_brand() {
+        let good = isobmff_box(b"ftyp", b"mp42\0\0\0\0");
+        assert!(looks_like_mp4(&good, 0));
+        assert!(!looks_like_mp4(&isobmff_box(b"ftyp", b"heic\0\0\0\0"), 0));
+        assert!(!looks_like_mp4(&isobmff_box(b"ftyp", b"mp4"), 0));
+
+        let mut truncated = good.clone();
+        truncated[3] = 0xF0;
+        assert!(!looks_like_mp4(&truncated, 0));
+    }
+
+    #[test]
+    fn edge_matting_weights_by_alpha() {
+        let mut img = image::RgbaImage::from_pixel(8, 8, image::Rgba([10, 20, 30, 255]));
+        for x in 0..8 {
+            let px = if x % 2 == 0 { [255, 0, 0, 255] } else { [0, 0, 255, 0] };
+            for y in 0..EDGE_STRIP_PX {
+                img.put_pixel(x, y, image::Rgba(px));
+            }
+        }
+        let matting = compute_edge_matting(&img);
+        assert_eq!(matting.top, [255, 0, 0]);
+        assert_eq!(matting.bottom, [10, 20, 30]);
+        assert!(matting.transparent_edges);
+    }
+
+    #[test]
+    fn edge_matting_of_transparent_strips_is_black() {
+        let matting = compute_edge_matting(&image::RgbaImage::from_pixel(6, 6, image::Rgba([200, 200, 200, 0])));
+        assert_eq!(matting.top, [0, 0, 0]);
+        assert_eq!(matting.average, [0, 0, 0]);
+        assert!(matting.transparent_edges);
+    }
+
+    #[test]
+    fn edge_matting_handles_images_smaller_than_a_strip() {
+        let one = compute_edge_matting(&image::RgbaImage::from_pixel(1, 1, image::Rgba([5, 6, 7, 255])));
+        assert_eq!([one.top, one.right, one.bottom, one.left, one.average], [[5, 6, 7]; 5]);
+        assert!(!one.transparent_edges);
+
+        let small = compute_edge_matting(&image::RgbaImage::from_pixel(3, 2, image::Rgba([9, 9, 9, 255])));
+        assert_eq!(small.average, [9, 9, 9]);
+
+        let empty = compute_edge_matting(&image::RgbaImage::new(0, 0));
+        assert_eq!(empty.average, [0, 0, 0]);
+        assert!(!empty.transparent_edges);
+    }
+
+    #[test]
+    fn edge_matting_flags_only_transparent_borders() {
+        let opaque = image::RgbaImage::from_pixel(10, 10, image::Rgba([1, 2, 3, 255]));
+        assert!(!compute_edge_matting(&opaque.clone()).transparent_edges);
+
+        let mut holed = opaque.clone();
+        holed.put_pixel(5, 5, image::Rgba([0, 0, 0, 0]));
+        assert!(!compute_edge_matting(&holed).transparent_edges);
+
+        let mut bordered = image::RgbaImage::from_pixel(10, 10, image::Rgba([0, 0, 0, 0]));
+        image::imageops::replace(&mut bordered, &image::RgbaImage::from_pixel(8, 8, image::Rgba([1, 2, 3, 255])), 1, 1);
+        assert!(compute_edge_matting(&bordered).transparent_edges);
+    }
+
+    #[test]
+    fn edge_matting_average_counts_corners_once() {
+        // Black 4x4 corners on white: the 256-pixel border ring is 1/4 black.
+        let mut img = image::RgbaImage::from_pixel(20, 20, image::Rgba([255, 255, 255, 255]));
+        let corner = image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 0, 0, 255]));
+        for (x, y) in [(0, 0), (16, 0), (0, 16), (16, 16)] {
+            image::imageops::replace(&mut img, &corner, x, y);
+        }
+        let matting = compute_edge_matting(&img);
+        assert_eq!(matting.average, [191, 191, 191]);
+        assert_eq!(matting.top, [153, 153, 153]);
+    }
+
+    /// Fresh empty directory under the system temp dir, unique per test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("yupic-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn has_transparent_pixels_needs_a_non_opaque_pixel() {
+        let rgb = image::DynamicImage::ImageRgb8(image::RgbImage::new(4, 4));
+        assert!(!has_transparent_pixels(&rgb));
+
+        let mut rgba = image::RgbaImage::from_pixel(4, 4, image::Rgba([9, 9, 9, 255]));
+        assert!(!has_transparent_pixels(&image::DynamicImage::ImageRgba8(rgba.clone())));
+        rgba.put_pixel(3, 3, image::Rgba([9, 9, 9, 254]));
+        assert!(has_transparent_pixels(&image::DynamicImage::ImageRgba8(rgba)));
+
+        let la = image::GrayAlphaImage::from_pixel(2, 2, image::LumaA([0, 0]));
+        assert!(has_transparent_pixels(&image::DynamicImage::ImageLumaA8(la)));
+    }
+
+    #[test]
+    fn parse_drag_format_normalizes_names() {
+        assert_eq!(parse_drag_format(None), Ok(None));
+        assert_eq!(parse_drag_format(Some("PNG")), Ok(Some("png")));
+        assert_eq!(parse_drag_format(Some("jpeg")), Ok(Some("jpg")));
+        assert!(parse_drag_format(Some("tiff")).is_err());
+    }
+
+    #[test]
+    fn drag_export_path_keys_on_source_and_format() {
+        let cache = Path::new("/cache");
+        let jpg = drag_export_path(cache, Path::new("/photos/IMG_0001.HEIC"), "jpg");
+        assert_eq!(jpg, drag_export_path(cache, Path::new("/photos/IMG_0001.HEIC"), "jpg"));
+        assert_eq!(jpg.parent(), Some(cache));
+        let name = jpg.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("IMG_0001-") && name.ends_with(".jpg"), "{name}");
+
+        let png = drag_export_path(cache, Path::new("/photos/IMG_0001.HEIC"), "png");
+        assert_eq!(png.with_extension("jpg"), jpg);
+        assert_ne!(jpg, drag_export_path(cache, Path::new("/other/IMG_0001.HEIC"), "jpg"));
+    }
+
+    #[test]
+    fn evict_drag_exports_drops_expired_then_oldest() {
+        let dir = scratch_dir("drag-evict");
+        let now = SystemTime::now();
+        let touch = |name: &str, age_secs: u64| {
+            let path = dir.join(name);
+            let file = std::fs::File::create(&path).unwrap();
+            file.set_modified(now - std::time::Duration::from_secs(age_secs)).unwrap();
+            path
+        };
+
+        let expired = touch("expired.jpg", DRAG_EXPORT_MAX_AGE.as_secs() + 60);
+        let oldest = touch("oldest.jpg", 3600);
+        for i in 0..MAX_DRAG_EXPORTS {
+            touch(&format!("recent-{i}.jpg"), 60 + i as u64);
+        }
+        // `keep` is older than everything else but must survive.
+        let keep = touch("keep.png", 7200);
+
+        evict_drag_exports(&keep);
+        assert!(keep.exists());
+        assert!(!expired.exists());
+        assert!(!oldest.exists());
+        let left = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(left, MAX_DRAG_EXPORTS);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn directory_snapshots_survive_lookups_until_evicted() {
+        let snapshots = DirectorySnapshots::default();
+        let dir = Path::new("/photos");
+        let first = snapshots.insert(dir.to_path_buf(), false, HashMap::new());
+        assert!(snapshots.get(first, dir, false).is_some());
+        assert!(snapshots.get(first, dir, false).is_some());
+        assert!(snapshots.get(first, Path::new("/other"), false).is_none());
+        assert!(snapshots.get(first, dir, true).is_none());
+
+        // The lookup above made `first` most recent, so the next oldest goes.
+        let second = snapshots.insert(dir.to_path_buf(), false, HashMap::new());
+        for _ in 1..MAX_DIRECTORY_SNAPSHOTS {
+            snapshots.get(first, dir, false);
+            snapshots.insert(dir.to_path_buf(), false, HashMap::new());
+        }
+        assert!(snapshots.get(first, dir, false).is_some());
+        assert!(snapshots.get(second, dir, false).is_none());
+    }
+
+    #[test]
+    fn directory_scan_lists_documents_only_on_request() {
+        let dir = scratch_dir("scan-documents");
+        std::fs::write(dir.join("photo.JPG"), b"jpeg").unwrap();
+        std::fs::write(dir.join("report.docx"), b"PK").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"text").unwrap();
+
+        let names = |include_documents| {
+            let mut names: Vec<String> = scan_directory_images(&dir, include_documents)
+                .unwrap()
+                .into_keys()
+                .map(|path| Path::new(&path).file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(false), ["photo.JPG"]);
+        assert_eq!(names(true), ["photo.JPG", "report.docx"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn diff_entries_reports_added_removed_and_changed() {
+        let stamp = |len, secs| EntryStamp {
+            len,
+            modified: Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)),
+        };
+        let previous = HashMap::from([
+            ("/p/same.jpg".to_string(), stamp(10, 100)),
+            ("/p/resized.jpg".to_string(), stamp(10, 100)),
+            ("/p/touched.jpg".to_string(), stamp(10, 100)),
+            ("/p/gone.jpg".to_string(), stamp(10, 100)),
+        ]);
+        let current = HashMap::from([
+            ("/p/same.jpg".to_string(), stamp(10, 100)),
+            ("/p/resized.jpg".to_string(), stamp(12, 100)),
+            ("/p/touched.jpg".to_string(), stamp(10, 200)),
+            ("/p/b.jpg".to_string(), stamp(1, 1)),
+            ("/p/a.jpg".to_string(), stamp(1, 1)),
+        ]);
+
+        let (added, removed, changed) = diff_entries(&previous, &current);
+        assert_eq!(added, ["/p/a.jpg", "/p/b.jpg"]);
+        assert_eq!(removed, ["/p/gone.jpg"]);
+        assert_eq!(changed, ["/p/resized.jpg", "/p/touched.jpg"]);
+
+        // An unknown token diffs against nothing: everything is added.
+        let (added, removed, changed) = diff_entries(&HashMap::new(), &previous);
+        assert_eq!(added.len(), 4);
+        assert!(removed.is_empty() && changed.is_empty());
+    }
+
+    #[test]
+    fn decode_workers_clamp_threads_and_background_pool() {
+        let workers = build_decode_workers(DecodePoolConfig {
+            max_threads: 10_000,
+            reserved_foreground: 10_000,
+            background_low_priority: false,
+        })
+        .unwrap();
+        assert_eq!(workers.config.max_threads, MAX_DECODE_THREADS);
+        assert_eq!(workers.config.reserved_foreground, MAX_DECODE_THREADS - 1);
+        assert_eq!(workers.foreground.current_num_threads(), MAX_DECODE_THREADS);
+        assert_eq!(workers.background.current_num_threads(), 1);
+
+        let workers = build_decode_workers(DecodePoolConfig {
+            max_threads: 0,
+            reserved_foreground: 1,
+            background_low_priority: false,
+        })
+        .unwrap();
+        assert_eq!(workers.config.max_threads, 1);
+        assert_eq!(workers.background.current_num_threads(), 1);
+    }
+
+    /// Polls `future` to completion on the current thread.
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        struct Unpark(std::thread::Thread);
+        impl std::task::Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = std::task::Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn foreground_decode_runs_while_background_saturates_its_pool() {
+        use rayon::prelude::*;
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        let workers = Arc::new(
+            build_decode_workers(DecodePoolConfig {
+                max_threads: 4,
+                reserved_foreground: 1,
+                background_low_priority: false,
+            })
+            .unwrap(),
+        );
+        let started = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(AtomicBool::new(false));
+
+        // A background job whose inner parallelism blocks every thread it reaches.
+        let background = {
+            let (workers, started, release) = (Arc::clone(&workers), Arc::clone(&started), Arc::clone(&release));
+            std::thread::spawn(move || {
+                block_on(run_decode(workers, DecodePriority::Background, move || {
+                    (0..64).into_par_iter().for_each(|_| {
+                        started.fetch_add(1, Ordering::SeqCst);
+                        while !release.load(Ordering::SeqCst) {
+                            std::thread::sleep(std::time::Duration::from_millis(1));
+                        }
+                    });
+                }))
+            })
+        };
+        while started.load(Ordering::SeqCst) < 3 {
+            std::thread::yield_now();
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let foreground = Arc::clone(&workers);
+        std::thread::spawn(move || {
+            let sum = block_on(run_decode(foreground, DecodePriority::Foreground, || {
+                (0..1000u64).into_par_iter().sum::<u64>()
+            }));
+            let _ = tx.send(sum);
+        });
+        let sum = rx.recv_timeout(std::time::Duration::from_secs(10));
+        // Only the three background threads ever picked up blocking items.
+        assert_eq!(started.load(Ordering::SeqCst), 3);
+        release.store(true, Ordering::SeqCst);
+
+        assert_eq!(sum.expect("foreground job blocked behind background work"), Ok(499_500));
+        assert!(background.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn gif_frame_count_walks_blocks() {
+        // 1x1 GIF89a with a two-entry global color table.
+        let mut gif = b"GIF89a\x01\0\x01\0\x80\0\0".to_vec();
+        gif.extend([0, 0, 0, 255, 255, 255]);
+        let frame = [
+            0x21, 0xF9, 4, 0, 10, 0, 0, 0, // graphic control extension
+            0x2C, 0, 0, 0, 0, 1, 0, 1, 0, 0, // image descriptor
+            2, 2, 0x44, 0x01, 0, // LZW code size + one data sub-block
+        ];
+        gif.extend(frame);
+        gif.extend(frame);
+        assert_eq!(gif_frame_count(&gif[..]), Ok(2));
+
+        let mut terminated = gif.clone();
+        terminated.push(0x3B);
+        assert_eq!(gif_frame_count(&terminated[..]), Ok(2));
+
+        assert!(gif_frame_count(&gif[..gif.len() - 3]).is_err());
+        assert!(gif_frame_count(&b"PNG89a\x01\0\x01\0\0\0\0"[..]).is_err());
+    }
+
+    fn tiny_png() -> Vec<u8> {
+        let mut out = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(image::RgbaImage::new(2, 2))
+            .write_to(&mut out, image::ImageOutputFormat::Png)
+            .unwrap();
+        out.into_inner()
+    }
+
+    /// ZIP with one entry per `(name, method, stored bytes, uncompressed size)`.
+    fn fake_zip(entries: &[(&str, u16, &[u8], usize)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, method, stored, uncompressed) in entries {
+            let local = out.len() as u32;
+            let mut sizes = (stored.len() as u32).to_le_bytes().to_vec();
+            sizes.extend((uncompressed as u32).to_le_bytes());
+
+            out.extend(b"PK\x03\x04\x14\0\0\0");
+            out.extend(method.to_le_bytes());
+            out.extend([0u8; 8]); // time, date, crc
+            out.extend(&sizes);
+            out.extend((name.len() as u16).to_le_bytes());
+            out.extend([0, 0]);
+            out.extend(name.as_bytes());
+            out.extend(stored);
+
+            directory.extend(b"PK\x01\x02\x14\0\x14\0\0\0");
+            directory.extend(method.to_le_bytes());
+            directory.extend([0u8; 8]);
+            directory.extend(&sizes);
+            directory.extend((name.len() as u16).to_le_bytes());
+            directory.extend([0u8; 12]); // extra, comment, disk, attributes
+            directory.extend(local.to_le_bytes());
+            directory.extend(name.as_bytes());
+        }
+        let directory_at = out.len() as u32;
+        out.extend(&directory);
+        out.extend(b"PK\x05\x06\0\0\0\0");
+        out.extend((entries.len() as u16).to_le_bytes());
+        out.extend((entries.len() as u16).to_le_bytes());
+        out.extend((directory.len() as u32).to_le_bytes());
+        out.extend(directory_at.to_le_bytes());
+        out.extend([0, 0]);
+        out
+    }
+
+    #[test]
+    fn zip_thumbnail_inflates_preview_entry() {
+        use std::io::Write;
+
+        let png = tiny_png();
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&png).unwrap();
+        let deflated = encoder.finish().unwrap();
+
+        let zip = fake_zip(&[
+            ("word/document.xml", 0, b"<w:document/>", 13),
+            ("docProps/thumbnail.png", 8, &deflated, png.len()),
+        ]);
+        let (img, source) = zip_thumbnail(&mut std::io::Cursor::new(&zip)).expect("thumbnail");
+        assert_eq!(source, "zip:docProps/thumbnail.png");
+        assert_eq!((img.width(), img.height()), (2, 2));
+
+        // Inflation stops at the declared size, so an understated entry can't decode.
+        let short = fake_zip(&[("docProps/thumbnail.png", 8, &deflated, png.len() / 2)]);
+        assert!(zip_thumbnail(&mut std::io::Cursor::new(&short)).is_none());
+
+        assert!(zip_thumbnail(&mut std::io::Cursor::new(&png)).is_none());
+    }
+
+    #[test]
+    fn zip_without_thumbnail_skips_magic_scan() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(image::RgbaImage::new(64, 64))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        // A stored media entry the scan would otherwise mistake for a preview.
+        let zip = fake_zip(&[("word/media/image1.png", 0, &png, png.len())]);
+        assert!(scan_embedded_image(&zip).is_some());
+
+        let dir = scratch_dir("zip-preview");
+        let path = dir.join("report.docx");
+        std::fs::write(&path, &zip).unwrap();
+        assert!(load_embedded_preview(&path).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn xmp_thumbnail_reads_element_and_attribute_forms() {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(tiny_png());
+        let (head, tail) = encoded.split_at(encoded.len() / 2);
+
+        let element = format!("<xmpGImg:image>{head}&#xA;{tail}</xmpGImg:image>");
+        let (img, source) = xmp_thumbnail(element.as_bytes()).expect("element form");
+        assert_eq!(source, "xmp");
+        assert_eq!(img.width(), 2);
+
+        let attribute = format!("<rdf:li xmpGImg:width=\"2\" xmpGImg:image=\"{encoded}\"/>");
+        assert!(xmp_thumbnail(attribute.as_bytes()).is_some());
+
+        assert!(xmp_thumbnail(b"<xmpGImg:image>not base64!</xmpGImg:image>").is_none());
+    }
+
+    /// A 2x2 `grid` HEIC: descriptor in idat, tiles 2..=5, tile 2 tagged hvcC Main,
+    /// the grid itself rotated a quarter turn and mirrored about the horizontal axis.
+    #[cfg(feature = "heif")]
+    fn fake_heic_grid(major_brand: &[u8; 4]) -> Vec<u8> {
+        let full = |kind: &[u8; 4], version: u8, body: &[u8]| {
+            let mut payload = vec![version, 0, 0, 0];
+            payload.extend_from_slice(body);
+            isobmff_box(kind, &payload)
+        };
+        let mut ftyp = major_brand.to_vec();
+        ftyp.extend(b"\0\0\0\0mif1heic");
+
+        let mut iinf_body = vec![0, 1];
+        iinf_body.extend(full(b"infe", 2, b"\0\x01\0\0grid\0"));
+        let dimg = isobmff_box(b"dimg", &[0, 1, 0, 4, 0, 2, 0, 3, 0, 4, 0, 5]);
+        // iloc v1: 4-byte offset/length, no base offset, item 1 via idat.
+        let iloc = [0x44, 0x00, 0, 1, 0, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 8];
+        let grid = [0, 0, 1, 1, 0x03, 0xE8, 0x03, 0x20];
+        let mut ipco = isobmff_box(b"hvcC", &[1, 0x01, 0, 0]);
+        ipco.extend(isobmff_box(b"irot", &[1]));
+        ipco.extend(isobmff_box(b"imir", &[1]));
+        let mut iprp = isobmff_box(b"ipco", &ipco);
+        iprp.extend(full(b"ipma", 0, &[0, 0, 0, 2, 0, 2, 1, 0x81, 0, 1, 2, 0x82, 0x83]));
+
+        let mut meta = full(b"pitm", 0, &[0, 1]);
+        meta.extend(full(b"iinf", 0, &iinf_body));
+        meta.extend(full(b"iref", 0, &dimg));
+        meta.extend(full(b"iloc", 1, &iloc));
+        meta.extend(isobmff_box(b"idat", &grid));
+        meta.extend(isobmff_box(b"iprp", &iprp));
+
+        let mut out = isobmff_box(b"ftyp", &ftyp);
+        out.extend(full(b"meta", 0, &meta));
+        out
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn heif_container_info_reads_grid_layout() {
+        let info = heif_container_info(&fake_heic_grid(b"heic")).expect("parses");
+        assert_eq!(info.major_brand, "heic");
+        assert_eq!(info.compatible_brands, ["mif1", "heic"]);
+        assert_eq!(info.primary_item_type, "grid");
+        assert_eq!(info.grid_tiles, [2, 3, 4, 5]);
+        assert_eq!(
+            info.grid_layout,
+            Some(HeifGridLayout { rows: 2, columns: 2, width: 1000, height: 800 })
+        );
+        assert_eq!(info.hevc_profile, Some(1));
+        assert_eq!(
+            info.transforms,
+            [HeifTransform::Rotate(1), HeifTransform::Mirror { vertical_axis: false }]
+        );
+        assert!(!info.is_unsupported_variant());
+
+        let dolby = heif_container_info(&fake_heic_grid(b"dvhe")).expect("parses");
+        assert!(dolby.is_unsupported_variant());
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn heif_grid_layout_handles_wide_dimensions() {
+        let mut wide = vec![0, 1, 3, 7];
+        wide.extend(70_000u32.to_be_bytes());
+        wide.extend(40_000u32.to_be_bytes());
+        assert_eq!(
+            heif_grid_layout(&wide),
+            Some(HeifGridLayout { rows: 4, columns: 8, width: 70_000, height: 40_000 })
+        );
+        assert_eq!(heif_grid_layout(&[1, 0, 0, 0, 0, 1, 0, 1]), None);
+    }
+
+    #[cfg(feature = "heif")]
+    fn solid_tile(shade: u8) -> image::RgbaImage {
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([shade, shade, shade, 255]))
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn stitch_grid_tiles_places_and_crops_tiles() {
+        // 3x2 grid of 2x2 tiles cropped to 5x3.
+        let layout = HeifGridLayout { rows: 2, columns: 3, width: 5, height: 3 };
+        let tiles = [10, 11, 12, 13, 14, 15];
+        let canvas = stitch_grid_tiles(layout, &tiles, (2, 2), |id| Ok(solid_tile(id as u8))).unwrap();
+        assert_eq!(canvas.dimensions(), (5, 3));
+        assert_eq!(canvas.get_pixel(0, 0).0[0], 10);
+        assert_eq!(canvas.get_pixel(4, 0).0[0], 12);
+        assert_eq!(canvas.get_pixel(2, 2).0[0], 14);
+        assert_eq!(canvas.get_pixel(4, 2).0[0], 15);
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn stitch_grid_tiles_rejects_bad_layouts_before_decoding() {
+        let never = |_| -> Result<image::RgbaImage, String> { panic!("tile decoded before layout check") };
+        let layout = HeifGridLayout { rows: 2, columns: 2, width: 4, height: 4 };
+
+        assert!(stitch_grid_tiles(layout, &[1, 2, 3], (2, 2), never).is_err());
+        // Output larger than the tiles cover, or smaller by a whole tile.
+        let wide = HeifGridLayout { width: 5, ..layout };
+        assert!(stitch_grid_tiles(wide, &[1, 2, 3, 4], (2, 2), never).is_err());
+        let narrow = HeifGridLayout { width: 2, ..layout };
+        assert!(stitch_grid_tiles(narrow, &[1, 2, 3, 4], (2, 2), never).is_err());
+        // Consistent but enormous: 8x4 tiles of 8750x10000 make 70000x40000.
+        let huge = HeifGridLayout { rows: 4, columns: 8, width: 70_000, height: 40_000 };
+        let ids: Vec<u32> = (0..32).collect();
+        assert!(stitch_grid_tiles(huge, &ids, (8750, 10_000), never).is_err());
+
+        let mismatched = stitch_grid_tiles(layout, &[1, 2, 3, 4], (2, 2), |id| {
+            Ok(if id == 3 { image::RgbaImage::new(1, 2) } else { solid_tile(0) })
+        });
+        assert!(mismatched.is_err());
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn apply_heif_transforms_rotates_anticlockwise_then_mirrors() {
+        let mut img = image::RgbaImage::new(2, 1);
+        img.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        img.put_pixel(1, 0, image::Rgba([0, 0, 255, 255]));
+        let img = image::DynamicImage::ImageRgba8(img);
+
+        let rotated = apply_heif_transforms(img.clone(), &[HeifTransform::Rotate(1)]).to_rgba8();
+        assert_eq!(rotated.dimensions(), (1, 2));
+        assert_eq!(rotated.get_pixel(0, 0).0, [0, 0, 255, 255]);
+
+        let mirrored = apply_heif_transforms(img.clone(), &[HeifTransform::Mirror { vertical_axis: true }]);
+        assert_eq!(mirrored.to_rgba8().get_pixel(0, 0).0, [0, 0, 255, 255]);
+
+        let both = apply_heif_transforms(
+            img,
+            &[HeifTransform::Rotate(1), HeifTransform::Mirror { vertical_axis: false }],
+        );
+        assert_eq!(both.to_rgba8().get_pixel(0, 0).0, [255, 0, 0, 255]);
+    }
+
+    #[cfg(feature = "heif")]
+    #[test]
+    fn heif_item_location_applies_base_offset() {
+        // iloc v0: 4-byte offset/length/base, two items; item 7 at 100 + 20.
+        let mut iloc = vec![0, 0, 0, 0, 0x44, 0x40, 0, 2];
+        iloc.extend([0, 3, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 4]);
+        iloc.extend([0, 7, 0, 0, 0, 0, 0, 100, 0, 1, 0, 0, 0, 20, 0, 0, 0, 12]);
+        assert_eq!(heif_item_location(&iloc, 7), Some((0, 120, 12)));
+        assert_eq!(heif_item_location(&iloc, 9), None);
+    }
+
+    fn blank_frame(width: u32, height: u32) -> ImageFrame {
+        let data = vec![0; width as usize * height as usize * 4];
+        ImageFrame {
+            width,
+            height,
+            delay_ms: 0,
+            decoded_bytes: data.len() as u64,
+            data,
+            matting: None,
+        }
+    }
+
+    #[test]
+    fn payload_limit_suggests_smaller_size() {
+        // A 100x100 frame is 40000 raw bytes, 53336 once base64-encoded.
+        let frames = [blank_frame(100, 100), blank_frame(100, 60)];
+        assert_eq!(check_payload_limit(&frames[..1], 0).ok(), Some(53336));
+        assert_eq!(check_payload_limit(&frames[..1], 53336).ok(), Some(53336));
+
+        let payload = 53336 + base64_len(100 * 60 * 4);
+        let Err(err) = check_payload_limit(&frames, 50_000) else {
+            panic!("payload over the limit was accepted");
+        };
+        // sqrt(50000 / 85336) * 100 = 76.5
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            serde_json::json!({
+                "code": "payload_too_large",
+                "payload_bytes": payload,
+                "limit_bytes": 50_000,
+                "suggested_max_size": 76,
+            })
+        );
+
+        // Tiny limits still suggest at least one pixel.
+        let Err(OpenImageError::PayloadTooLarge { suggested_max_size, .. }) = check_payload_limit(&frames, 1) else {
+            panic!("payload over the limit was accepted");
+        };
+        assert_eq!(suggested_max_size, 1);
+    }
+
+    #[test]
+    fn open_image_errors_and_frames_serialize_for_frontend() {
+        let err = OpenImageError::from("no frames decoded");
+        assert_eq!(serde_json::to_value(&err).unwrap(), serde_json::json!("no frames decoded"));
+
+        let frame = serde_json::to_value(blank_frame(1, 1)).unwrap();
+        assert_eq!(frame["data"], "AAAAAA==");
+        assert_eq!(frame["decoded_bytes"], 4);
+        assert!(frame.get("matting").is_none());
+    }
+}